@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+use crate::database::{PresenceJob, UserIdentity};
+
+/// A part of a presence that can count as a change. Chosen with the comma separated `CHANGE_FIELDS` env var.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeField {
+    Status,
+    Platforms,
+    Activities,
+    Details,
+    State,
+    Timestamps,
+    Assets,
+    Party
+}
+
+impl ChangeField {
+    fn parse(name: &str) -> Option<ChangeField> {
+        match name.trim() {
+            "status" => Some(ChangeField::Status),
+            "platforms" => Some(ChangeField::Platforms),
+            "activities" => Some(ChangeField::Activities),
+            "details" => Some(ChangeField::Details),
+            "state" => Some(ChangeField::State),
+            "timestamps" => Some(ChangeField::Timestamps),
+            "assets" => Some(ChangeField::Assets),
+            "party" => Some(ChangeField::Party),
+            _ => None
+        }
+    }
+}
+
+const DEFAULT_FIELDS: &str = "status,platforms,activities,details,state";
+
+/// Remembers the last written state of every (guild, user) pair so repeated presence ticks can be dropped.
+pub struct ChangeDetector {
+    fields: Vec<ChangeField>,
+    last_seen: Mutex<HashMap<(u64, u64), Vec<String>>>
+}
+
+impl ChangeDetector {
+    pub fn new(fields: Vec<ChangeField>) -> Self {
+        ChangeDetector {
+            fields,
+            last_seen: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let configured = env::var("CHANGE_FIELDS").unwrap_or(String::from(DEFAULT_FIELDS));
+        let fields = configured
+            .split(',')
+            .filter_map(|name| {
+                let field = ChangeField::parse(name);
+                if field.is_none() {
+                    println!("Ignoring unknown change field {name}");
+                }
+                field
+            })
+            .collect();
+
+        ChangeDetector::new(fields)
+    }
+
+    /// Returns true when the job differs from the last one seen for the same user in any configured field,
+    /// plays a different Spotify track or the same one from a different position, or shows another custom status.
+    pub fn is_change(&self, job: &PresenceJob) -> bool {
+        let fingerprint = self.fingerprint(job);
+        let mut last_seen = self.last_seen.lock().unwrap();
+
+        if last_seen.get(&(job.guild_id, job.user_id)) == Some(&fingerprint) {
+            return false;
+        }
+
+        last_seen.insert((job.guild_id, job.user_id), fingerprint);
+        true
+    }
+
+    fn fingerprint(&self, job: &PresenceJob) -> Vec<String> {
+        // A replayed or seeked track only differs in its start, and every play has to reach the plays summary
+        let mut parts: Vec<String> = job.spotify
+            .iter()
+            .map(|play| format!("{} {}", play.track_id, play.started_at))
+            .collect();
+
+        // Custom status history is only kept from written presences, whichever fields are configured
+        if let Some(custom_status) = &job.custom_status {
+            parts.push(format!("{:?} {:?}", custom_status.text, custom_status.emoji));
+        }
+
+        for field in self.fields.iter() {
+            match field {
+                ChangeField::Status => parts.push(job.status.clone()),
+                ChangeField::Platforms => parts.push(format!("{:?}/{:?}/{:?}", job.desktop_status, job.mobile_status, job.web_status)),
+                ChangeField::Activities => parts.extend(job.activities.iter().map(|a| format!("{} {}", a.kind, a.name))),
+                ChangeField::Details => parts.extend(job.activities.iter().map(|a| format!("{:?}", a.details))),
+                ChangeField::State => parts.extend(job.activities.iter().map(|a| format!("{:?}", a.state))),
+                ChangeField::Timestamps => parts.extend(job.activities.iter().map(|a| format!("{:?}-{:?}", a.started_at, a.ends_at))),
+                ChangeField::Assets => parts.extend(job.activities.iter().map(|a| format!("{:?}/{:?}", a.large_text, a.small_text))),
+                ChangeField::Party => parts.extend(job.activities.iter().map(|a| format!("{:?}/{:?}", a.party_size, a.party_max)))
+            }
+        }
+
+        parts
+    }
+}
+
+/// Remembers the last identity queued for every (guild, user) pair, so events of a user who looks the same
+/// as before do not write their identity again.
+#[derive(Default)]
+pub struct IdentityTracker {
+    last_queued: Mutex<HashMap<(u64, u64), UserIdentity>>
+}
+
+impl IdentityTracker {
+    /// Returns true when the identity differs from the last one seen for the same user.
+    pub fn is_change(&self, identity: &UserIdentity) -> bool {
+        let mut last_queued = self.last_queued.lock().unwrap();
+        if last_queued.get(&(identity.guild_id, identity.user_id)) == Some(identity) {
+            return false;
+        }
+
+        last_queued.insert((identity.guild_id, identity.user_id), identity.clone());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::CustomStatus;
+    use crate::fixtures;
+
+    fn job(status: &str, activity: Option<&str>) -> PresenceJob {
+        fixtures::presence(2, 100, status, activity)
+    }
+
+    #[test]
+    fn repeated_presences_are_not_changes() {
+        let detector = ChangeDetector::new(vec!(ChangeField::Status, ChangeField::Activities));
+        assert!(detector.is_change(&job("online", Some("Game"))));
+        assert!(!detector.is_change(&job("online", Some("Game"))));
+        assert!(detector.is_change(&job("idle", Some("Game"))));
+        assert!(detector.is_change(&job("idle", None)));
+    }
+
+    #[test]
+    fn fields_that_are_not_configured_are_ignored() {
+        let detector = ChangeDetector::new(vec!(ChangeField::Status));
+        let mut moved = job("online", Some("Game"));
+        moved.activities[0].started_at = Some(200);
+
+        assert!(detector.is_change(&job("online", Some("Game"))));
+        assert!(!detector.is_change(&moved));
+        assert!(!detector.is_change(&job("online", Some("Other game"))));
+    }
+
+    #[test]
+    fn users_are_compared_separately() {
+        let detector = ChangeDetector::new(vec!(ChangeField::Status));
+        let mut other = job("online", None);
+        other.user_id = 3;
+
+        assert!(detector.is_change(&job("online", None)));
+        assert!(detector.is_change(&other));
+    }
+
+    #[test]
+    fn spotify_seeks_are_changes_with_any_fields() {
+        let detector = ChangeDetector::new(vec!(ChangeField::Status));
+        let mut playing = job("online", Some("Spotify"));
+        playing.spotify = Some(fixtures::spotify_play(2, "track", 100, None));
+        let mut seeked = playing.clone();
+        seeked.spotify = Some(fixtures::spotify_play(2, "track", 160, None));
+
+        assert!(detector.is_change(&playing));
+        assert!(!detector.is_change(&playing));
+        assert!(detector.is_change(&seeked));
+    }
+
+    #[test]
+    fn custom_status_emoji_changes_are_changes_with_any_fields() {
+        let detector = ChangeDetector::new(vec!(ChangeField::Status));
+        let mut smiling = job("online", None);
+        smiling.custom_status = Some(CustomStatus { text: Some(String::from("Working")), emoji: Some(String::from("🙂")) });
+        let mut sleeping = smiling.clone();
+        sleeping.custom_status = Some(CustomStatus { text: Some(String::from("Working")), emoji: Some(String::from("😴")) });
+
+        assert!(detector.is_change(&smiling));
+        assert!(!detector.is_change(&smiling));
+        assert!(detector.is_change(&sleeping));
+    }
+
+    #[test]
+    fn identities_are_only_changes_when_something_differs() {
+        let tracker = IdentityTracker::default();
+        let identity = UserIdentity {
+            guild_id: 1,
+            user_id: 2,
+            username: String::from("name"),
+            global_name: None,
+            nickname: None,
+            avatar: None
+        };
+        let renamed = UserIdentity { nickname: Some(String::from("nick")), ..identity.clone() };
+
+        assert!(tracker.is_change(&identity));
+        assert!(!tracker.is_change(&identity));
+        assert!(tracker.is_change(&renamed));
+    }
+}
@@ -1,127 +1,1558 @@
-use std::{collections::HashMap, fs::File};
-use libsql::{Builder, Row};
-use log::{info, error};
-use tokio::sync::mpsc::{Sender, Receiver, channel};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::Duration;
+use libsql::Builder;
+use log::error;
+use poise::serenity_prelude as serenity;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use crate::migrations::{self, MigrationError};
+use crate::retention::Cutoffs;
+use crate::scope::TrackingRules;
+use crate::spool::SpooledJob;
+use crate::store::{Store, StoreError};
 
+/// Everything the bot records goes through the write queue as one of these.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WriteJob {
+    Presence(Box<PresenceJob>),
+    Voice(VoiceEvent),
+    Message(MessageEvent),
+    Member(MemberEvent),
+    Identity(IdentityJob)
+}
+
+/// A user seen with a name, nickname or avatar that differs from the last one queued for them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdentityJob {
+    pub identity: UserIdentity,
+    pub time: u64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceJob {
+    pub guild_id: u64,
+    pub user_id: u64,
+    pub time: u64,
+    pub status: String,
+    pub activity: String,
+    pub activity_description: String,
+    pub desktop_status: Option<String>,
+    pub mobile_status: Option<String>,
+    pub web_status: Option<String>,
+    pub activities: Vec<ActivityRecord>,
+    pub spotify: Option<SpotifyPlay>,
+    pub custom_status: Option<CustomStatus>,
+    pub source: String
+}
+
+/// A change in someone's voice state, such as joining a channel or muting themselves.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoiceEvent {
+    pub guild_id: u64,
+    pub user_id: u64,
+    pub time: u64,
+    pub kind: String,
+    pub channel_id: Option<u64>,
+    pub channel_name: Option<String>,
+    pub previous_channel_id: Option<u64>,
+    pub previous_channel_name: Option<String>
+}
+
+/// Metadata of a sent message. The content itself is never stored; `length` and `attachments` are only known with `LOG_MESSAGE_LENGTH` set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageEvent {
+    pub guild_id: u64,
+    pub user_id: u64,
+    pub channel_id: u64,
+    pub channel_name: Option<String>,
+    pub time: u64,
+    pub length: Option<u32>,
+    pub attachments: Option<u32>,
+    pub is_reply: bool,
+    pub in_thread: bool
+}
+
+/// A member joining, leaving or having their nickname or roles changed. Roles are stored by name, one per line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberEvent {
+    pub guild_id: u64,
+    pub user_id: u64,
+    pub time: u64,
+    pub kind: String,
+    pub nick_before: Option<String>,
+    pub nick_after: Option<String>,
+    pub roles_added: Vec<String>,
+    pub roles_removed: Vec<String>
+}
+
+/// One entry of `Presence.activities`, stored in `presence_activities` with its position in the list.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActivityRecord {
+    pub position: u32,
+    pub name: String,
+    pub details: Option<String>,
+    pub kind: String,
+    pub state: Option<String>,
+    pub started_at: Option<u64>,
+    pub ends_at: Option<u64>,
+    pub party_size: Option<u32>,
+    pub party_max: Option<u32>,
+    pub application_id: Option<u64>,
+    pub large_text: Option<String>,
+    pub small_text: Option<String>,
+    pub url: Option<String>,
+    pub emoji: Option<String>
+}
+
+/// The text and emoji of a custom status. Only changes are kept in `custom_statuses`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CustomStatus {
+    pub text: Option<String>,
+    pub emoji: Option<String>
+}
+
+/// A row of `custom_statuses`. `status` is `None` for the moment a custom status was cleared.
+#[derive(Debug, Clone)]
+pub struct CustomStatusChange {
+    pub user_id: u64,
+    pub time: u64,
+    pub status: Option<CustomStatus>
+}
+
+/// A Spotify track taken from a presence. Consecutive presences for the same play collapse into one `spotify_plays` row.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpotifyPlay {
+    pub guild_id: u64,
+    pub user_id: u64,
+    pub track_id: String,
+    pub title: String,
+    pub artist: String,
+    pub album: Option<String>,
+    pub started_at: u64,
+    pub ends_at: Option<u64>
+}
+
+/// What a user was called in a guild. Identical consecutive identities share one `user_identities` row.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserIdentity {
+    pub guild_id: u64,
+    pub user_id: u64,
+    pub username: String,
+    pub global_name: Option<String>,
+    pub nickname: Option<String>,
+    pub avatar: Option<String>
+}
+
+/// A row of the `guilds` table. Presences from guilds that are not enabled are ignored.
 #[derive(Debug)]
-pub struct WriteJob {
+pub struct GuildConfig {
+    pub guild_id: u64,
+    pub name: String,
+    pub enabled: bool,
+    /// Overrides `RETENTION_DAYS` for the guild's raw events, 0 keeps them forever.
+    pub retention_days: Option<u64>
+}
+
+/// An interval during which a user kept one status or activity. `ended_at` is `None` while it is still going.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub user_id: u64,
+    pub kind: String,
+    pub value: String,
+    pub started_at: u64,
+    pub ended_at: Option<u64>,
+    pub end_reason: Option<String>
+}
+
+/// A period during which a shard was connected and recording. Anything outside of these is missing data, not inactivity.
+#[derive(Debug, Clone)]
+pub struct CoveragePeriod {
+    pub shard_id: u32,
+    pub started_at: u64,
+    pub last_seen: u64,
+    pub ended_at: Option<u64>,
+    pub start_reason: String,
+    pub end_reason: Option<String>
+}
+
+/// A row of `tracking_data` as shown on the dashboard. Rows from before guilds and sources were recorded have neither.
+#[derive(Debug, Clone)]
+pub struct PresenceRow {
+    pub id: u64,
+    pub guild_id: Option<u64>,
     pub user_id: u64,
     pub time: u64,
     pub status: String,
     pub activity: String,
-    pub activity_description: String
+    pub activity_description: String,
+    pub desktop_status: Option<String>,
+    pub mobile_status: Option<String>,
+    pub web_status: Option<String>,
+    pub source: Option<String>
+}
+
+/// Dashboard filters for `get_data`. Every field left as `None` is not applied.
+#[derive(Debug, Default)]
+pub struct DataFilter<'a> {
+    pub guild_id: Option<&'a str>,
+    pub user_id: Option<&'a str>,
+    pub status: Option<&'a str>,
+    pub activity: Option<&'a str>,
+    pub activity_description: Option<&'a str>,
+    pub activity_type: Option<&'a str>,
+    pub activity_state: Option<&'a str>,
+    pub application_id: Option<&'a str>,
+    pub platform: Option<&'a str>,
+    pub custom_status: Option<&'a str>,
+    pub time_lt: Option<&'a u64>,
+    pub time_mt: Option<&'a u64>
 }
 
-pub fn new_write_queue(buffer: usize) -> (Sender<WriteJob>, Receiver<WriteJob>) {
-    channel(buffer)
+/// The open database, built once in `main` and shared by the handler, the writer and the webserver.
+/// Reads and small writes go through the shared connection. Anything running a transaction takes the writer
+/// connection, so unrelated statements never end up inside it and transactions run one after another.
+pub struct Database {
+    conn: libsql::Connection,
+    writer: Mutex<libsql::Connection>
 }
 
-pub async fn associate_usermame(id: u64, name: &str) {
-    println!("Associating user {id} with username {name}");
+/// The writer holds a transaction for every batch, so other connections wait for it instead of failing as locked.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Database {
+    /// Opens the database file and applies pending migrations.
+    pub async fn open(path: &str) -> Result<Database, MigrationError> {
+        let db = Builder::new_local(path).build().await?;
+        let conn = db.connect()?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        // Only takes effect on a new file, older ones are switched once with --vacuum
+        conn.execute("PRAGMA auto_vacuum = INCREMENTAL", ()).await?;
+        migrations::run(&conn).await?;
+
+        // Every connection to ":memory:" opens a database of its own, so there the writer shares the migrated one
+        let writer = if path == ":memory:" {
+            conn.clone()
+        } else {
+            let writer = db.connect()?;
+            writer.busy_timeout(BUSY_TIMEOUT)?;
+            writer
+        };
+        Ok(Database { conn, writer: Mutex::new(writer) })
+    }
+
+    /// Prints the migrations `open` would apply to the file, without changing it. A missing file is not
+    /// created, every migration is listed against an empty in-memory database instead.
+    pub async fn print_pending_migrations(path: &str) -> Result<(), MigrationError> {
+        let exists = Path::new(path).exists();
+        if !exists {
+            println!("{path} does not exist yet, a new database gets every migration");
+        }
+        let db = Builder::new_local(if exists { path } else { ":memory:" }).build().await?;
+        let conn = db.connect()?;
+        migrations::print_pending(&conn).await
+    }
+
+    /// Rewrites a file created before retention existed so freed pages can be given back incrementally.
+    /// Runs on its own connection before the bot starts, since VACUUM needs the file to itself.
+    pub async fn switch_to_incremental_vacuum(path: &str) -> Result<(), libsql::Error> {
+        let db = Builder::new_local(path).build().await?;
+        let conn = db.connect()?;
+        if auto_vacuum_mode(&conn).await? == AUTO_VACUUM_INCREMENTAL {
+            println!("The database already uses incremental vacuum");
+            return Ok(());
+        }
+
+        println!("Switching the database to incremental vacuum, this rewrites the file once");
+        conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;").await?;
+        println!("Done");
+        Ok(())
+    }
+}
+
+#[serenity::async_trait]
+impl Store for Database {
+    async fn add_tracking_rule(&self, guild_id: u64, kind: &str, target_id: u64) {
+        let result: Result<u64, libsql::Error> = self.conn.execute(
+            "INSERT OR IGNORE INTO tracking_rules (guild_id, kind, target_id) VALUES (?1, ?2, ?3)",
+            (guild_id, kind, target_id)
+        ).await;
+        if let Err(e) = result {
+            error!("Failed to add tracking rule {}", e);
+        }
+    }
+
+    async fn remove_tracking_rule(&self, guild_id: u64, kind: &str, target_id: u64) -> bool {
+        let result: Result<u64, libsql::Error> = self.conn.execute(
+            "DELETE FROM tracking_rules WHERE guild_id = ?1 AND kind = ?2 AND target_id = ?3",
+            (guild_id, kind, target_id)
+        ).await;
+        match result {
+            Ok(removed) => removed > 0,
+            Err(e) => {
+                error!("Failed to remove tracking rule {}", e);
+                false
+            }
+        }
+    }
+
+    async fn set_track_bots(&self, guild_id: u64, track_bots: bool) {
+        let result: Result<u64, libsql::Error> = self.conn.execute(
+            "INSERT INTO guilds (guild_id, track_bots) VALUES (?1, ?2) ON CONFLICT(guild_id) DO UPDATE SET track_bots = excluded.track_bots",
+            (guild_id, track_bots)
+        ).await;
+        if let Err(e) = result {
+            error!("Failed to update guild {}", e);
+        }
+    }
+
+    async fn get_tracking_rules(&self, guild_id: u64) -> Result<TrackingRules, StoreError> {
+        let mut rules = TrackingRules::default();
+
+        let mut rows = self.conn.query("SELECT track_bots FROM guilds WHERE guild_id = ?1", [guild_id]).await?;
+        if let Some(row) = rows.next().await? {
+            rules.track_bots = row.get::<Option<bool>>(0)?.unwrap_or(false);
+        }
+
+        let mut rows = self.conn.query("SELECT kind, target_id FROM tracking_rules WHERE guild_id = ?1", [guild_id]).await?;
+        while let Some(row) = rows.next().await? {
+            let kind: String = row.get(0)?;
+            let target_id: u64 = row.get(1)?;
+            match kind.as_str() {
+                "include_role" => rules.include_roles.push(target_id),
+                "exclude_role" => rules.exclude_roles.push(target_id),
+                "allow_user" => rules.allow_users.push(target_id),
+                "deny_user" => rules.deny_users.push(target_id),
+                _ => println!("Ignoring unknown tracking rule {kind}")
+            }
+        }
+
+        Ok(rules)
+    }
+
+    async fn register_guild(&self, guild_id: u64, name: &str) {
+        let result: Result<u64, libsql::Error> = self.conn.execute(
+            "INSERT INTO guilds (guild_id, name) VALUES (?1, ?2) ON CONFLICT(guild_id) DO UPDATE SET name = excluded.name",
+            (guild_id, name)
+        ).await;
+        if let Err(e) = result {
+            error!("Failed to register guild {}", e);
+        }
+    }
+
+    async fn set_guild_enabled(&self, guild_id: u64, enabled: bool) {
+        let result: Result<u64, libsql::Error> = self.conn.execute(
+            "INSERT INTO guilds (guild_id, enabled) VALUES (?1, ?2) ON CONFLICT(guild_id) DO UPDATE SET enabled = excluded.enabled",
+            (guild_id, enabled)
+        ).await;
+        if let Err(e) = result {
+            error!("Failed to update guild {}", e);
+        }
+    }
+
+    async fn is_guild_enabled(&self, guild_id: u64) -> Result<bool, StoreError> {
+        let mut rows = self.conn.query("SELECT enabled FROM guilds WHERE guild_id = ?1", [guild_id]).await?;
+        match rows.next().await? {
+            Some(row) => Ok(row.get::<bool>(0)?),
+            None => Ok(false)
+        }
+    }
+
+    async fn get_guilds(&self) -> Vec<GuildConfig> {
+        let mut guilds: Vec<GuildConfig> = vec!();
+        let mut rows = self.conn.query("SELECT guild_id, name, enabled, retention_days FROM guilds ORDER BY name", ()).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            guilds.push(GuildConfig {
+                guild_id: row.get(0).unwrap(),
+                name: row.get::<Option<String>>(1).unwrap().unwrap_or(String::from("Unknown guild")),
+                enabled: row.get(2).unwrap(),
+                retention_days: row.get(3).unwrap()
+            });
+        }
+        guilds
+    }
+
+    async fn set_retention_days(&self, guild_id: u64, days: Option<u64>) {
+        let result: Result<u64, libsql::Error> = self.conn.execute(
+            "INSERT INTO guilds (guild_id, retention_days) VALUES (?1, ?2) ON CONFLICT(guild_id) DO UPDATE SET retention_days = excluded.retention_days",
+            (guild_id, days.map(|days| days as i64))
+        ).await;
+        if let Err(e) = result {
+            error!("Failed to update guild {}", e);
+        }
+    }
+
+    async fn count_expired(&self, table: &str, cutoffs: &Cutoffs) -> Vec<(Option<u64>, u64)> {
+        let mut counts: Vec<(Option<u64>, u64)> = vec!();
+        let mut rows = self.conn.query(
+            format!("SELECT guild_id, COUNT(*) FROM {table} WHERE {} GROUP BY guild_id", expired_condition(cutoffs)).as_str(),
+            ()
+        ).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            counts.push((row.get(0).unwrap(), row.get(1).unwrap()));
+        }
+        counts
+    }
+
+    /// One batch is one transaction, and the activities of a presence go first so none are left without it.
+    async fn delete_expired(&self, table: &str, cutoffs: &Cutoffs, limit: u64) -> Result<u64, StoreError> {
+        let conn = self.writer.lock().await;
+        let transaction = conn.transaction().await?;
+
+        let mut ids: Vec<String> = vec!();
+        let mut rows = transaction.query(
+            format!("SELECT id FROM {table} WHERE {} LIMIT {limit}", expired_condition(cutoffs)).as_str(),
+            ()
+        ).await?;
+        while let Some(row) = rows.next().await? {
+            ids.push(row.get::<u64>(0)?.to_string());
+        }
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let id_list = ids.join(", ");
+        if table == "tracking_data" {
+            transaction.execute(format!("DELETE FROM presence_activities WHERE tracking_id IN ({id_list})").as_str(), ()).await?;
+        }
+        let deleted = transaction.execute(format!("DELETE FROM {table} WHERE id IN ({id_list})").as_str(), ()).await?;
+
+        transaction.commit().await?;
+        Ok(deleted)
+    }
+
+    /// Files created before retention existed keep their size until they are switched with `--vacuum`.
+    async fn incremental_vacuum(&self) {
+        match auto_vacuum_mode(&self.conn).await {
+            Ok(AUTO_VACUUM_INCREMENTAL) => {
+                // Frees one page per step, execute_batch runs it to the end
+                if let Err(e) = self.conn.execute_batch("PRAGMA incremental_vacuum;").await {
+                    error!("Failed to vacuum {}", e);
+                }
+            },
+            Ok(_) => println!("Pruned rows stay in the file until it is switched to incremental vacuum with --vacuum"),
+            Err(e) => error!("Failed to read the vacuum mode {}", e)
+        }
+    }
+
+    async fn open_coverage(&self, shard_id: u32, reason: &str, time: u64) {
+        self.close_coverage(Some(shard_id), "reconnected", time).await;
+
+        let result: Result<u64, libsql::Error> = self.conn.execute(
+            "INSERT INTO coverage (shard_id, started_at, last_seen, start_reason) VALUES (?1, ?2, ?3, ?4)",
+            (shard_id, time, time, reason)
+        ).await;
+        if let Err(e) = result {
+            error!("Failed to open coverage period {}", e);
+        }
+    }
+
+    async fn close_coverage(&self, shard_id: Option<u32>, reason: &str, time: u64) {
+        let mut query = String::from("UPDATE coverage SET ended_at = ?1, last_seen = ?1, end_reason = ?2 WHERE ended_at IS NULL");
+        if let Some(shard_id) = shard_id {
+            query += &format!(" AND shard_id = {shard_id}");
+        }
+
+        let result: Result<u64, libsql::Error> = self.conn.execute(&query, (time, reason)).await;
+        if let Err(e) = result {
+            error!("Failed to close coverage period {}", e);
+        }
+    }
+
+    async fn touch_coverage(&self, time: u64) {
+        let result: Result<u64, libsql::Error> = self.conn.execute("UPDATE coverage SET last_seen = ?1 WHERE ended_at IS NULL", [time]).await;
+        if let Err(e) = result {
+            error!("Failed to update coverage heartbeat {}", e);
+        }
+    }
 
-    let db = Builder::new_local("data.db").build().await.unwrap();
-    let conn = db.connect().unwrap();
-    
-    conn.execute(format!("
-    CREATE TABLE IF NOT EXISTS users (
-        id                      INTEGER PRIMARY KEY,
-        username                MEDIUMTEXT
-    )
-    ").as_str(), ()).await.unwrap();
+    async fn close_abandoned_coverage(&self) {
+        let result: Result<u64, libsql::Error> = self.conn.execute(
+            "UPDATE coverage SET ended_at = last_seen, end_reason = 'crash' WHERE ended_at IS NULL",
+            ()
+        ).await;
+        match result {
+            Ok(closed) if closed > 0 => println!("Closed {closed} coverage periods left open by the previous run"),
+            Ok(_) => {},
+            Err(e) => error!("Failed to close abandoned coverage {}", e)
+        }
+    }
 
-    let result: Result<u64, libsql::Error> = conn.execute(
-        "INSERT OR REPLACE INTO users (id, username) VALUES (?1, ?2)",
-        (id, name)
-    ).await;
-} 
+    async fn start_run(&self, time: u64) -> Result<u64, StoreError> {
+        let closed = self.conn.execute(
+            "UPDATE runs SET ended_at = COALESCE((SELECT MAX(last_seen) FROM coverage), started_at) WHERE ended_at IS NULL",
+            ()
+        ).await?;
+        if closed > 0 {
+            println!("The previous run did not shut down cleanly");
+        }
 
-pub async fn get_usernames(ids: Vec<u64>) -> HashMap<u64,String> {
-    let db = Builder::new_local("data.db").build().await.unwrap();
-    let conn = db.connect().unwrap();
+        self.conn.execute("INSERT INTO runs (started_at) VALUES (?1)", [time]).await?;
+        Ok(self.conn.last_insert_rowid() as u64)
+    }
 
-    let mut results: HashMap<u64, String> = HashMap::new();
+    async fn finish_run(&self, run_id: u64, time: u64) {
+        let result: Result<u64, libsql::Error> = self.conn.execute(
+            "UPDATE runs SET ended_at = ?1, clean_shutdown = 1 WHERE id = ?2",
+            [time, run_id]
+        ).await;
+        if let Err(e) = result {
+            error!("Failed to mark the run as cleanly shut down {}", e);
+        }
+    }
+
+    async fn get_coverage(&self, limit: u64) -> Vec<CoveragePeriod> {
+        let mut periods: Vec<CoveragePeriod> = vec!();
+        let mut rows = self.conn.query(
+            format!("SELECT shard_id, started_at, last_seen, ended_at, start_reason, end_reason FROM coverage ORDER BY started_at DESC LIMIT {limit}").as_str(),
+            ()
+        ).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            periods.push(CoveragePeriod {
+                shard_id: row.get(0).unwrap(),
+                started_at: row.get(1).unwrap(),
+                last_seen: row.get(2).unwrap(),
+                ended_at: row.get(3).unwrap(),
+                start_reason: row.get(4).unwrap(),
+                end_reason: row.get(5).unwrap()
+            });
+        }
+        periods
+    }
+
+    async fn get_coverage_gaps(&self, from: u64, to: u64, now: u64) -> Vec<(u64, u64)> {
+        let mut rows = self.conn.query(
+            "SELECT started_at, COALESCE(ended_at, ?1) FROM coverage WHERE started_at <= ?2 ORDER BY started_at",
+            [now, to]
+        ).await.unwrap();
 
-    conn.execute(format!("
-    CREATE TABLE IF NOT EXISTS users (
-        id                      INTEGER PRIMARY KEY,
-        username                MEDIUMTEXT
-    )
-    ").as_str(), ()).await.unwrap();
+        let mut periods: Vec<(u64, u64)> = vec!();
+        while let Ok(Some(row)) = rows.next().await {
+            periods.push((row.get(0).unwrap(), row.get(1).unwrap()));
+        }
+        coverage_gaps(periods, from, to, now)
+    }
 
-    for id in ids.iter() {
-        let mut query = conn.query(&format!("SELECT username FROM users WHERE id = {id}").as_str(),()).await.unwrap();
-        if let Some(row) = query.next().await.unwrap() {
-            results.insert(*id, row.get(0).unwrap());
+    async fn set_opted_out(&self, user_id: u64, opted_out: bool, time: u64) {
+        let result: Result<u64, libsql::Error> = if opted_out {
+            self.conn.execute("INSERT OR IGNORE INTO opted_out (user_id, time) VALUES (?1, ?2)", [user_id, time]).await
         } else {
-            println!("Could not find value for {id}");
-            results.insert(*id, "unknown-user".to_string());
+            self.conn.execute("DELETE FROM opted_out WHERE user_id = ?1", [user_id]).await
+        };
+        if let Err(e) = result {
+            error!("Failed to update opt out {}", e);
+        }
+    }
+
+    async fn get_opted_out(&self) -> Result<HashSet<u64>, StoreError> {
+        let mut opted_out: HashSet<u64> = HashSet::new();
+        let mut rows = self.conn.query("SELECT user_id FROM opted_out", ()).await?;
+        while let Some(row) = rows.next().await? {
+            opted_out.insert(row.get(0)?);
+        }
+        Ok(opted_out)
+    }
+
+    /// Deletes everything recorded about a user in one transaction. Returns how many rows each table lost.
+    async fn forget_user(&self, user_id: u64) -> Result<Vec<(&'static str, u64)>, StoreError> {
+        let conn = self.writer.lock().await;
+
+        let transaction = conn.transaction().await?;
+        let mut removed: Vec<(&'static str, u64)> = vec!();
+
+        for (table, condition) in USER_TABLES {
+            let exists = transaction
+                .query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1", [table])
+                .await?
+                .next()
+                .await?
+                .is_some();
+            if !exists {
+                continue;
+            }
+
+            let deleted = transaction.execute(format!("DELETE FROM {table} WHERE {condition}").as_str(), [user_id]).await?;
+            removed.push((table, deleted));
+        }
+
+        transaction.commit().await?;
+        Ok(removed)
+    }
+
+    async fn get_names_at(&self, lookups: Vec<(u64, u64, u64)>) -> HashMap<(u64, u64, u64), String> {
+        let mut results: HashMap<(u64, u64, u64), String> = HashMap::new();
+        let mut missing: Vec<u64> = vec!();
+
+        for (guild_id, user_id, time) in lookups.iter() {
+            let mut rows = self.conn.query(
+                "SELECT COALESCE(nickname, global_name, username) FROM user_identities WHERE guild_id = ?1 AND user_id = ?2 AND first_seen <= ?3 ORDER BY first_seen DESC LIMIT 1",
+                [*guild_id, *user_id, *time]
+            ).await.unwrap();
+            let mut row = rows.next().await.unwrap();
+
+            if row.is_none() {
+                rows = self.conn.query(
+                    "SELECT COALESCE(nickname, global_name, username) FROM user_identities WHERE guild_id = ?1 AND user_id = ?2 ORDER BY first_seen LIMIT 1",
+                    [*guild_id, *user_id]
+                ).await.unwrap();
+                row = rows.next().await.unwrap();
+            }
+
+            match row {
+                Some(row) => {
+                    results.insert((*guild_id, *user_id, *time), row.get(0).unwrap());
+                },
+                None => missing.push(*user_id)
+            }
+        }
+
+        if !missing.is_empty() {
+            let current = self.get_usernames(missing).await;
+            for lookup in lookups.iter() {
+                if let Some(name) = current.get(&lookup.1) {
+                    results.entry(*lookup).or_insert(name.clone());
+                }
+            }
+        }
+
+        results
+    }
+
+    async fn get_username(&self, user_id: u64) -> Result<Option<String>, StoreError> {
+        let mut rows = self.conn.query("SELECT username FROM users WHERE id = ?1", [user_id]).await?;
+        match rows.next().await? {
+            Some(row) => Ok(row.get::<Option<String>>(0)?),
+            None => Ok(None)
+        }
+    }
+
+    async fn get_usernames(&self, ids: Vec<u64>) -> HashMap<u64,String> {
+        let mut results: HashMap<u64, String> = HashMap::new();
+
+        for id in ids.iter() {
+            let mut query = self.conn.query(format!("SELECT username FROM users WHERE id = {id}").as_str(),()).await.unwrap();
+            if let Some(row) = query.next().await.unwrap() {
+                results.insert(*id, row.get(0).unwrap());
+            } else {
+                println!("Could not find value for {id}");
+            }
+        }
+        results
+    }
+
+    /// Returns every activity recorded for the given `tracking_data` rows, keyed by row id and ordered by position.
+    async fn get_activities(&self, tracking_ids: Vec<u64>) -> HashMap<u64, Vec<ActivityRecord>> {
+        let mut results: HashMap<u64, Vec<ActivityRecord>> = HashMap::new();
+        if tracking_ids.is_empty() {
+            return results;
+        }
+
+        let id_list = tracking_ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", ");
+        let mut rows = self.conn.query(
+            format!("
+            SELECT tracking_id, position, name, details, kind, state, started_at, ends_at, party_size, party_max, application_id, large_text, small_text, url, emoji
+            FROM presence_activities WHERE tracking_id IN ({id_list}) ORDER BY tracking_id, position
+            ").as_str(),
+            ()
+        ).await.unwrap();
+
+        while let Ok(Some(row)) = rows.next().await {
+            let tracking_id: u64 = row.get(0).unwrap();
+            results.entry(tracking_id).or_default().push(ActivityRecord {
+                position: row.get(1).unwrap(),
+                name: row.get(2).unwrap(),
+                details: row.get(3).unwrap(),
+                kind: row.get::<Option<String>>(4).unwrap().unwrap_or(String::from("Unknown")),
+                state: row.get(5).unwrap(),
+                started_at: row.get(6).unwrap(),
+                ends_at: row.get(7).unwrap(),
+                party_size: row.get(8).unwrap(),
+                party_max: row.get(9).unwrap(),
+                application_id: row.get(10).unwrap(),
+                large_text: row.get(11).unwrap(),
+                small_text: row.get(12).unwrap(),
+                url: row.get(13).unwrap(),
+                emoji: row.get(14).unwrap()
+            });
+        }
+
+        results
+    }
+
+    /// Writes all jobs in one transaction. Jobs that fail, or the whole batch if it cannot be committed, are returned.
+    async fn write_batch(&self, jobs: Vec<WriteJob>) -> Vec<WriteJob> {
+        let size = jobs.len();
+        let conn = self.writer.lock().await;
+        let transaction = match conn.transaction().await {
+            Ok(transaction) => transaction,
+            Err(e) => {
+                error!("DB write failed, could not begin a transaction for {} jobs {}", size, e);
+                return jobs;
+            }
+        };
+
+        let mut written: Vec<WriteJob> = Vec::with_capacity(size);
+        let mut failed: Vec<WriteJob> = vec!();
+        for job in jobs {
+            match write_job(&transaction, job.clone(), None).await {
+                Ok(()) => written.push(job),
+                Err(e) => {
+                    error!("DB write failed {}", e);
+                    failed.push(job);
+                }
+            }
+        }
+
+        match transaction.commit().await {
+            Ok(()) => println!("Wrote batch of {} jobs", size),
+            Err(e) => {
+                error!("DB write failed, batch of {} jobs was not committed {}", size, e);
+                failed.append(&mut written);
+            }
+        }
+        failed
+    }
+
+    async fn replay(&self, jobs: Vec<SpooledJob>) -> Result<Vec<SpooledJob>, StoreError> {
+        let conn = self.writer.lock().await;
+        let transaction = conn.transaction().await?;
+        let mut failed: Vec<SpooledJob> = vec!();
+
+        for spooled in jobs {
+            if let Err(e) = write_job(&transaction, spooled.job.clone(), Some(&spooled.job_id)).await {
+                error!("Replaying spooled job {} failed {}", spooled.job_id, e);
+                failed.push(spooled);
+            }
+        }
+
+        transaction.commit().await?;
+        Ok(failed)
+    }
+
+    async fn close_stale_sessions(&self) {
+        let result: Result<u64, libsql::Error> = self.conn.execute("
+        UPDATE sessions
+        SET ended_at = MAX(started_at, COALESCE((SELECT MAX(ended_at) FROM coverage), (SELECT MAX(time) FROM tracking_data), started_at)), end_reason = 'restart'
+        WHERE ended_at IS NULL
+        ", ()).await;
+
+        match result {
+            Ok(closed) if closed > 0 => println!("Closed {closed} sessions left open by the previous run"),
+            Ok(_) => {},
+            Err(e) => error!("Failed to close stale sessions {}", e)
+        }
+    }
+
+    async fn get_data(&self, page: &u64, filter: &DataFilter<'_>) -> Vec<PresenceRow> {
+        let (query, params) = data_query(page, filter);
+        println!("Executing query {query}");
+
+        let mut presences: Vec<PresenceRow> = vec!();
+        let mut rows = self.conn.query(&query, params).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            presences.push(PresenceRow {
+                id: row.get(0).unwrap(),
+                user_id: row.get(1).unwrap(),
+                time: row.get(2).unwrap(),
+                status: row.get(3).unwrap(),
+                activity: row.get(4).unwrap(),
+                activity_description: row.get(5).unwrap(),
+                desktop_status: row.get(6).unwrap(),
+                mobile_status: row.get(7).unwrap(),
+                web_status: row.get(8).unwrap(),
+                guild_id: row.get(9).unwrap(),
+                source: row.get(10).unwrap()
+            });
+        }
+        presences
+    }
+
+    /// `EXPLAIN QUERY PLAN` of the dashboard query as `(id, parent, detail)` rows, to see which filters scan the table.
+    async fn explain_data_query(&self, page: &u64, filter: &DataFilter<'_>) -> Result<Vec<(i64, i64, String)>, StoreError> {
+        let (query, params) = data_query(page, filter);
+        let mut plan: Vec<(i64, i64, String)> = vec!();
+
+        let mut rows = self.conn.query(format!("EXPLAIN QUERY PLAN {query}").as_str(), params).await?;
+        while let Some(row) = rows.next().await? {
+            plan.push((row.get(0)?, row.get(1)?, row.get(3)?));
+        }
+        Ok(plan)
+    }
+
+    async fn get_recent_plays(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<SpotifyPlay> {
+        let mut query = String::from("SELECT user_id, track_id, title, artist, album, started_at, ends_at, guild_id FROM spotify_plays");
+        let (conditions, params) = guild_user_conditions(guild_id, user_id);
+        query += &conditions;
+        query += &format!(" ORDER BY started_at DESC LIMIT {limit}");
+
+        let mut plays: Vec<SpotifyPlay> = vec!();
+        let mut rows = self.conn.query(&query, params).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            plays.push(read_spotify_play(&row));
+        }
+        plays
+    }
+
+    async fn get_top_artists(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<(String, u64)> {
+        let mut query = String::from("SELECT artist, COUNT(*) AS plays FROM spotify_plays");
+        let (conditions, params) = guild_user_conditions(guild_id, user_id);
+        query += &conditions;
+        query += &format!(" GROUP BY artist ORDER BY plays DESC LIMIT {limit}");
+
+        let mut artists: Vec<(String, u64)> = vec!();
+        let mut rows = self.conn.query(&query, params).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            artists.push((row.get(0).unwrap(), row.get(1).unwrap()));
+        }
+        artists
+    }
+
+    async fn get_top_tracks(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<(String, String, u64)> {
+        let mut query = String::from("SELECT title, artist, COUNT(*) AS plays FROM spotify_plays");
+        let (conditions, params) = guild_user_conditions(guild_id, user_id);
+        query += &conditions;
+        query += &format!(" GROUP BY track_id ORDER BY plays DESC LIMIT {limit}");
+
+        let mut tracks: Vec<(String, String, u64)> = vec!();
+        let mut rows = self.conn.query(&query, params).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            tracks.push((row.get(0).unwrap(), row.get(1).unwrap(), row.get(2).unwrap()));
+        }
+        tracks
+    }
+
+    async fn get_sessions(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<Session> {
+        let mut query = String::from("SELECT user_id, kind, value, started_at, ended_at, end_reason FROM sessions");
+        let (conditions, params) = guild_user_conditions(guild_id, user_id);
+        query += &conditions;
+        query += &format!(" ORDER BY started_at DESC LIMIT {limit}");
+
+        let mut sessions: Vec<Session> = vec!();
+        let mut rows = self.conn.query(&query, params).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            sessions.push(Session {
+                user_id: row.get(0).unwrap(),
+                kind: row.get(1).unwrap(),
+                value: row.get(2).unwrap(),
+                started_at: row.get(3).unwrap(),
+                ended_at: row.get(4).unwrap(),
+                end_reason: row.get(5).unwrap()
+            });
+        }
+        sessions
+    }
+
+    async fn get_activity_totals(&self, guild_id: Option<&str>, user_id: Option<&str>, now: u64, limit: u64) -> Vec<(String, u64)> {
+        let mut query = format!("SELECT user_id, value, started_at, COALESCE(ended_at, {now}) FROM sessions");
+        let (conditions, params) = guild_user_conditions(guild_id, user_id);
+        query += &conditions;
+        query += " AND kind = 'activity'";
+
+        let mut sessions: Vec<(u64, String, u64, u64)> = vec!();
+        let mut rows = self.conn.query(&query, params).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            sessions.push((row.get(0).unwrap(), row.get(1).unwrap(), row.get(2).unwrap(), row.get(3).unwrap()));
+        }
+        activity_totals(sessions, limit)
+    }
+
+    async fn get_voice_events(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<VoiceEvent> {
+        let mut query = String::from("SELECT guild_id, user_id, time, kind, channel_id, channel_name, previous_channel_id, previous_channel_name FROM voice_events");
+        let (conditions, params) = guild_user_conditions(guild_id, user_id);
+        query += &conditions;
+        query += &format!(" ORDER BY time DESC LIMIT {limit}");
+
+        let mut events: Vec<VoiceEvent> = vec!();
+        let mut rows = self.conn.query(&query, params).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            events.push(VoiceEvent {
+                guild_id: row.get(0).unwrap(),
+                user_id: row.get(1).unwrap(),
+                time: row.get(2).unwrap(),
+                kind: row.get(3).unwrap(),
+                channel_id: row.get(4).unwrap(),
+                channel_name: row.get(5).unwrap(),
+                previous_channel_id: row.get(6).unwrap(),
+                previous_channel_name: row.get(7).unwrap()
+            });
         }
+        events
+    }
+
+    async fn get_message_counts_by_user(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<(u64, u64)> {
+        let mut query = String::from("SELECT user_id, COUNT(*) AS messages FROM message_events");
+        let (conditions, params) = guild_user_conditions(guild_id, user_id);
+        query += &conditions;
+        query += &format!(" GROUP BY user_id ORDER BY messages DESC LIMIT {limit}");
+
+        let mut counts: Vec<(u64, u64)> = vec!();
+        let mut rows = self.conn.query(&query, params).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            counts.push((row.get(0).unwrap(), row.get(1).unwrap()));
+        }
+        counts
+    }
+
+    async fn get_message_counts_by_channel(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<(String, u64)> {
+        let mut query = String::from("SELECT channel_id, MAX(channel_name), COUNT(*) AS messages FROM message_events");
+        let (conditions, params) = guild_user_conditions(guild_id, user_id);
+        query += &conditions;
+        query += &format!(" GROUP BY channel_id ORDER BY messages DESC LIMIT {limit}");
+
+        let mut counts: Vec<(String, u64)> = vec!();
+        let mut rows = self.conn.query(&query, params).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            let channel_id: u64 = row.get(0).unwrap();
+            let channel_name: Option<String> = row.get(1).unwrap();
+            counts.push((channel_name.unwrap_or(channel_id.to_string()), row.get(2).unwrap()));
+        }
+        counts
+    }
+
+    async fn get_member_events(&self, guild_id: Option<&str>, user_ids: Vec<u64>, from: u64, to: u64) -> Vec<MemberEvent> {
+        let mut events: Vec<MemberEvent> = vec!();
+        if user_ids.is_empty() {
+            return events;
+        }
+
+        let id_list = user_ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", ");
+        let mut query = String::from("SELECT guild_id, user_id, time, kind, nick_before, nick_after, roles_added, roles_removed FROM member_events");
+        let (conditions, params) = guild_user_conditions(guild_id, None);
+        query += &conditions;
+        query += &format!(" AND user_id IN ({id_list}) AND time >= {from} AND time <= {to} ORDER BY time");
+
+        let split = |roles: Option<String>| -> Vec<String> {
+            roles.filter(|r| !r.is_empty()).map(|r| r.lines().map(String::from).collect()).unwrap_or_default()
+        };
+
+        let mut rows = self.conn.query(&query, params).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            events.push(MemberEvent {
+                guild_id: row.get(0).unwrap(),
+                user_id: row.get(1).unwrap(),
+                time: row.get(2).unwrap(),
+                kind: row.get(3).unwrap(),
+                nick_before: row.get(4).unwrap(),
+                nick_after: row.get(5).unwrap(),
+                roles_added: split(row.get(6).unwrap()),
+                roles_removed: split(row.get(7).unwrap())
+            });
+        }
+        events
+    }
+
+    async fn get_custom_status_history(&self, guild_id: Option<&str>, user_id: Option<&str>, search: Option<&str>, limit: u64) -> Vec<CustomStatusChange> {
+        let mut query = String::from("SELECT user_id, time, text, emoji FROM custom_statuses");
+        let (conditions, mut params) = guild_user_conditions(guild_id, user_id);
+        query += &conditions;
+        if let Some(search) = search {
+            params.push(contains_pattern(search).into());
+            query += &format!(" AND text LIKE ?{} ESCAPE '\\'", params.len());
+        }
+        query += &format!(" ORDER BY time DESC LIMIT {limit}");
+
+        let mut history: Vec<CustomStatusChange> = vec!();
+        let mut rows = self.conn.query(&query, params).await.unwrap();
+        while let Ok(Some(row)) = rows.next().await {
+            let text: Option<String> = row.get(2).unwrap();
+            let emoji: Option<String> = row.get(3).unwrap();
+            history.push(CustomStatusChange {
+                user_id: row.get(0).unwrap(),
+                time: row.get(1).unwrap(),
+                status: if text.is_none() && emoji.is_none() { None } else { Some(CustomStatus { text, emoji }) }
+            });
+        }
+        history
     }
-    return results
 }
 
-pub async fn get_data(page: &u64, user_id: Option<&str>, status: Option<&str>, activity: Option<&str>, activity_description: Option<&str>, time_lt: Option<&u64>, time_mt: Option<&u64>) -> libsql::Rows {
-    let db = Builder::new_local("data.db").build().await.unwrap();
-    let conn = db.connect().unwrap();
-    let page_content_amount = 15;
-    let min_id = (page -1) * ((page-1)*page_content_amount);
-    
-    let mut base_query = format!("SELECT * FROM tracking_data WHERE id IS NOT NULL");
+/// Presences shown per dashboard page.
+pub const PAGE_SIZE: u64 = 15;
 
-    if let Some(user_id) = user_id {
-        base_query += &format!(" AND user_id = {user_id}");
+/// How many presences the dashboard skips before the given page.
+pub fn page_offset(page: u64) -> u64 {
+    (page - 1) * ((page - 1) * PAGE_SIZE)
+}
+
+/// The dashboard query for a page of `tracking_data` and the values bound to its parameters.
+/// Built separately so the debug page can explain exactly what `get_data` runs.
+pub fn data_query(page: &u64, filter: &DataFilter<'_>) -> (String, Vec<libsql::Value>) {
+    let page_content_amount = PAGE_SIZE;
+    let min_id = page_offset(*page);
+
+    let mut base_query = String::from("SELECT * FROM tracking_data WHERE id IS NOT NULL");
+    let mut params: Vec<libsql::Value> = vec!();
+    // Every filter comes from a cookie, so values are only ever bound. Returns the placeholder for the value.
+    let mut bind = |value: libsql::Value| -> String {
+        params.push(value);
+        format!("?{}", params.len())
+    };
+
+    if let Some(guild_id) = filter.guild_id {
+        base_query += &format!(" AND guild_id = {}", bind(id_value(guild_id)));
+    }
+
+    if let Some(user_id) = filter.user_id {
+        base_query += &format!(" AND user_id = {}", bind(id_value(user_id)));
     }
-    
-    if let Some(status) = status {
-        base_query += &format!(" AND status = '{status}'");
+
+    if let Some(status) = filter.status {
+        base_query += &format!(" AND status = {}", bind(status.into()));
+    }
+
+    if let Some(activity) = filter.activity {
+        base_query += &format!(" AND activity = {}", bind(activity.into()));
+    }
+
+    if let Some(activity_description ) = filter.activity_description {
+        base_query += &format!(" AND activity_description = {}", bind(activity_description.into()));
+    }
+
+    if let Some(activity_type) = filter.activity_type {
+        base_query += &format!(" AND EXISTS (SELECT 1 FROM presence_activities pa WHERE pa.tracking_id = tracking_data.id AND pa.kind = {})", bind(activity_type.into()));
+    }
+
+    if let Some(activity_state) = filter.activity_state {
+        base_query += &format!(" AND EXISTS (SELECT 1 FROM presence_activities pa WHERE pa.tracking_id = tracking_data.id AND pa.state = {})", bind(activity_state.into()));
     }
 
-    if let Some(activity) = activity {
-        base_query += &format!(" AND activity = '{activity}'");
+    if let Some(application_id) = filter.application_id {
+        base_query += &format!(" AND EXISTS (SELECT 1 FROM presence_activities pa WHERE pa.tracking_id = tracking_data.id AND pa.application_id = {})", bind(id_value(application_id)));
     }
 
-    if let Some(activity_description ) = activity_description {
-        base_query += &format!(" AND activity_description = '{activity_description}'");
+    if let Some(custom_status) = filter.custom_status {
+        base_query += &format!(" AND EXISTS (SELECT 1 FROM presence_activities pa WHERE pa.tracking_id = tracking_data.id AND pa.kind = 'Custom' AND pa.state LIKE {} ESCAPE '\\')", bind(contains_pattern(custom_status).into()));
     }
 
-    if let Some(time_lt) = time_lt {
+    if let Some(platform) = filter.platform {
+        base_query += match platform {
+            "desktop" => " AND desktop_status IS NOT NULL AND desktop_status != 'offline'",
+            "mobile" => " AND mobile_status IS NOT NULL AND mobile_status != 'offline'",
+            "web" => " AND web_status IS NOT NULL AND web_status != 'offline'",
+            "desktop_only" => " AND desktop_status IS NOT NULL AND mobile_status IS NULL AND web_status IS NULL",
+            "mobile_only" => " AND mobile_status IS NOT NULL AND desktop_status IS NULL AND web_status IS NULL",
+            "web_only" => " AND web_status IS NOT NULL AND desktop_status IS NULL AND mobile_status IS NULL",
+            _ => ""
+        };
+    }
+
+    if let Some(time_lt) = filter.time_lt {
         base_query += &format!(" AND time < {time_lt}");
     }
 
-    if let Some(time_mt) = time_mt {
-        base_query += &format!(" AND time < {time_mt}");
+    if let Some(time_mt) = filter.time_mt {
+        base_query += &format!(" AND time > {time_mt}");
     }
 
     base_query += &format!(" LIMIT {page_content_amount} OFFSET {min_id}");
 
-    println!("Executing query {base_query}");
-
-    return conn.query(&base_query, ()).await.unwrap();
-}
-
-pub async fn writer_task(mut rx: Receiver<WriteJob>) {
-    let db = Builder::new_local("data.db").build().await.unwrap();
-    let conn = db.connect().unwrap();
-    
-    conn.execute(format!("
-    CREATE TABLE IF NOT EXISTS tracking_data (
-        id                      INTEGER PRIMARY KEY AUTOINCREMENT,
-        user_id                 INTEGER,
-        time                    INTEGER,
-        status                  TINYTEXT,
-        activity                MEDIUMTEXT,
-        activity_description    MEDIUMTEXT
-    )
-    ").as_str(), ()).await.unwrap();
-
-    while let Some(job) = rx.recv().await {
-        println!("Performing write job");
-        let result: Result<u64, libsql::Error> = conn.execute(
-            "INSERT INTO tracking_data (user_id, time, status, activity, activity_description) VALUES (?1, ?2, ?3, ?4, ?5)",
-            (job.user_id, job.time, job.status, job.activity, job.activity_description)
-        ).await;
-        if let Err(e) = result {
-            error!("DB write failed {}", e);
+    (base_query, params)
+}
+
+/// An id typed into a filter. One that is not a number matches nothing.
+fn id_value(id: &str) -> libsql::Value {
+    id.parse::<i64>().map_or(libsql::Value::Null, libsql::Value::Integer)
+}
+
+/// A `LIKE` pattern for `text` anywhere in the value. Wildcards typed by the user match literally, with `ESCAPE '\'`.
+fn contains_pattern(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{escaped}%")
+}
+
+/// Gaps between `from` and `to` not covered by any of the `(started_at, ended_at)` periods, which are ordered by start.
+/// Nothing after `now` counts as a gap, and without any periods the whole range is one.
+pub fn coverage_gaps(periods: Vec<(u64, u64)>, from: u64, to: u64, now: u64) -> Vec<(u64, u64)> {
+    let end = to.min(now);
+    let mut gaps: Vec<(u64, u64)> = vec!();
+    let mut covered_until = from;
+    for (started_at, ended_at) in periods {
+        if started_at > covered_until && covered_until < end {
+            gaps.push((covered_until, started_at.min(end)));
+        }
+        covered_until = covered_until.max(ended_at);
+    }
+
+    if covered_until < end {
+        gaps.push((covered_until, end));
+    }
+
+    gaps
+}
+
+/// Seconds spent in each activity, most first, from `(user_id, activity, started_at, ended_at)` sessions.
+/// Sessions are kept per guild, so overlapping sessions of the same user and activity are merged before they count.
+pub fn activity_totals(mut sessions: Vec<(u64, String, u64, u64)>, limit: u64) -> Vec<(String, u64)> {
+    sessions.sort();
+
+    let mut seconds: HashMap<String, u64> = HashMap::new();
+    let mut merged: Option<(u64, String, u64, u64)> = None;
+    for session in sessions {
+        if let Some(open) = &mut merged
+            && open.0 == session.0 && open.1 == session.1 && session.2 <= open.3 {
+            open.3 = open.3.max(session.3);
+            continue;
+        }
+        if let Some((_, value, started_at, ended_at)) = merged.replace(session) {
+            *seconds.entry(value).or_default() += ended_at.saturating_sub(started_at);
+        }
+    }
+    if let Some((_, value, started_at, ended_at)) = merged {
+        *seconds.entry(value).or_default() += ended_at.saturating_sub(started_at);
+    }
+
+    let mut totals: Vec<(String, u64)> = seconds.into_iter().collect();
+    totals.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    totals.truncate(limit as usize);
+    totals
+}
+
+const AUTO_VACUUM_INCREMENTAL: u64 = 2;
+
+async fn auto_vacuum_mode(conn: &libsql::Connection) -> Result<u64, libsql::Error> {
+    let mut rows = conn.query("PRAGMA auto_vacuum", ()).await?;
+    match rows.next().await? {
+        Some(row) => row.get(0),
+        None => Ok(0)
+    }
+}
+
+/// Selects the rows of a raw event table that are older than the cutoff of their guild.
+fn expired_condition(cutoffs: &Cutoffs) -> String {
+    let mut conditions: Vec<String> = cutoffs.guilds
+        .iter()
+        .filter_map(|(guild_id, cutoff)| cutoff.map(|cutoff| format!("(guild_id = {guild_id} AND time < {cutoff})")))
+        .collect();
+
+    if let Some(cutoff) = cutoffs.default {
+        if cutoffs.guilds.is_empty() {
+            conditions.push(format!("time < {cutoff}"));
+        } else {
+            let overridden = cutoffs.guilds.iter().map(|(guild_id, _)| guild_id.to_string()).collect::<Vec<String>>().join(", ");
+            conditions.push(format!("((guild_id IS NULL OR guild_id NOT IN ({overridden})) AND time < {cutoff})"));
+        }
+    }
+
+    if conditions.is_empty() {
+        return String::from("0");
+    }
+    conditions.join(" OR ")
+}
+
+/// Every table holding rows about a user, with the condition selecting them. Activities hang off
+/// `tracking_data` rows, so they come before their parents.
+const USER_TABLES: [(&str, &str); 10] = [
+    ("presence_activities", "tracking_id IN (SELECT id FROM tracking_data WHERE user_id = ?1)"),
+    ("tracking_data", "user_id = ?1"),
+    ("users", "id = ?1"),
+    ("spotify_plays", "user_id = ?1"),
+    ("sessions", "user_id = ?1"),
+    ("voice_events", "user_id = ?1"),
+    ("message_events", "user_id = ?1"),
+    ("member_events", "user_id = ?1"),
+    ("user_identities", "user_id = ?1"),
+    ("custom_statuses", "user_id = ?1")
+];
+
+/// Updates the current username and extends the user's identity history. Only a job newer than the stored
+/// username replaces it and `last_seen` never moves back, so spooled jobs can be replayed in any order and more than once.
+async fn write_identity(conn: &libsql::Connection, job: IdentityJob, job_id: Option<&str>) -> Result<(), libsql::Error> {
+    println!("Associating user {} with username {}", job.identity.user_id, job.identity.username);
+    conn.execute(
+        "INSERT INTO users (id, username, updated_at) VALUES (?1, ?2, ?3)
+        ON CONFLICT (id) DO UPDATE SET username = excluded.username, updated_at = excluded.updated_at
+        WHERE users.updated_at IS NULL OR users.updated_at <= excluded.updated_at",
+        (job.identity.user_id, job.identity.username.as_str(), job.time)
+    ).await?;
+    extend_identity_history(conn, &job.identity, job.time, job_id).await
+}
+
+/// A changed username, display name, nickname or avatar starts a new identity instead of overwriting the old one.
+async fn extend_identity_history(conn: &libsql::Connection, identity: &UserIdentity, time: u64, job_id: Option<&str>) -> Result<(), libsql::Error> {
+    let mut rows = conn.query(
+        "SELECT id, username, global_name, nickname, avatar FROM user_identities WHERE guild_id = ?1 AND user_id = ?2 ORDER BY last_seen DESC LIMIT 1",
+        [identity.guild_id, identity.user_id]
+    ).await?;
+
+    if let Some(row) = rows.next().await? {
+        let latest = UserIdentity {
+            guild_id: identity.guild_id,
+            user_id: identity.user_id,
+            username: row.get(1)?,
+            global_name: row.get(2)?,
+            nickname: row.get(3)?,
+            avatar: row.get(4)?
+        };
+
+        if latest == *identity {
+            let id: u64 = row.get(0)?;
+            conn.execute("UPDATE user_identities SET last_seen = MAX(last_seen, ?1) WHERE id = ?2", [time, id]).await?;
+            return Ok(());
+        }
+    }
+
+    conn.execute(
+        "INSERT OR IGNORE INTO user_identities (guild_id, user_id, username, global_name, nickname, avatar, first_seen, last_seen, job_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        (
+            identity.guild_id, identity.user_id, identity.username.as_str(), identity.global_name.as_deref(),
+            identity.nickname.as_deref(), identity.avatar.as_deref(), time, time, job_id
+        )
+    ).await?;
+    Ok(())
+}
+
+fn read_spotify_play(row: &libsql::Row) -> SpotifyPlay {
+    SpotifyPlay {
+        guild_id: row.get(7).unwrap(),
+        user_id: row.get(0).unwrap(),
+        track_id: row.get(1).unwrap(),
+        title: row.get(2).unwrap(),
+        artist: row.get(3).unwrap(),
+        album: row.get(4).unwrap(),
+        started_at: row.get(5).unwrap(),
+        ends_at: row.get(6).unwrap()
+    }
+}
+
+/// Filters on the guild and user picked on the dashboard, with the values to bind to them.
+fn guild_user_conditions(guild_id: Option<&str>, user_id: Option<&str>) -> (String, Vec<libsql::Value>) {
+    let mut conditions = String::from(" WHERE id IS NOT NULL");
+    let mut params: Vec<libsql::Value> = vec!();
+    if let Some(guild_id) = guild_id {
+        params.push(id_value(guild_id));
+        conditions += &format!(" AND guild_id = ?{}", params.len());
+    }
+    if let Some(user_id) = user_id {
+        params.push(id_value(user_id));
+        conditions += &format!(" AND user_id = ?{}", params.len());
+    }
+    (conditions, params)
+}
+
+/// Extends the user's latest play when the presence still belongs to it, otherwise starts a new one.
+async fn record_spotify_play(conn: &libsql::Connection, play: SpotifyPlay) -> Result<(), libsql::Error> {
+    let mut rows = conn.query(
+        "SELECT id, track_id, ends_at FROM spotify_plays WHERE guild_id = ?1 AND user_id = ?2 ORDER BY started_at DESC LIMIT 1",
+        [play.guild_id, play.user_id]
+    ).await?;
+
+    if let Some(row) = rows.next().await? {
+        let id: u64 = row.get(0)?;
+        let track_id: String = row.get(1)?;
+        let ends_at: Option<u64> = row.get(2)?;
+
+        if track_id == play.track_id && ends_at.is_none_or(|ends_at| play.started_at <= ends_at) {
+            conn.execute(
+                "UPDATE spotify_plays SET ends_at = ?1 WHERE id = ?2",
+                (play.ends_at.map(|t| t as i64), id)
+            ).await?;
+            return Ok(());
+        }
+    }
+
+    conn.execute(
+        "INSERT INTO spotify_plays (guild_id, user_id, track_id, title, artist, album, started_at, ends_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        (play.guild_id, play.user_id, play.track_id, play.title, play.artist, play.album, play.started_at, play.ends_at.map(|t| t as i64))
+    ).await?;
+    Ok(())
+}
+
+/// The `(kind, value)` pairs a presence job keeps open: its status and every distinct activity name.
+fn session_values(job: &PresenceJob) -> Vec<(String, String)> {
+    let mut values = vec!((String::from("status"), job.status.clone()));
+    for activity in job.activities.iter() {
+        let value = (String::from("activity"), activity.name.clone());
+        if !values.contains(&value) {
+            values.push(value);
+        }
+    }
+    values
+}
+
+/// Closes the user's open sessions that the new presence no longer contains and opens the ones it adds.
+async fn update_sessions(conn: &libsql::Connection, guild_id: u64, user_id: u64, time: u64, values: Vec<(String, String)>) -> Result<(), libsql::Error> {
+    let mut open: Vec<(u64, String, String)> = vec!();
+    let mut rows = conn.query(
+        "SELECT id, kind, value FROM sessions WHERE guild_id = ?1 AND user_id = ?2 AND ended_at IS NULL",
+        [guild_id, user_id]
+    ).await?;
+    while let Some(row) = rows.next().await? {
+        open.push((row.get(0)?, row.get(1)?, row.get(2)?));
+    }
+
+    for (id, kind, value) in open.iter() {
+        if !values.iter().any(|(k, v)| k == kind && v == value) {
+            conn.execute("UPDATE sessions SET ended_at = ?1, end_reason = 'changed' WHERE id = ?2", [time, *id]).await?;
         }
     }
-}
\ No newline at end of file
+
+    for (kind, value) in values {
+        if !open.iter().any(|(_, k, v)| *k == kind && *v == value) {
+            conn.execute(
+                "INSERT INTO sessions (guild_id, user_id, kind, value, started_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                (guild_id, user_id, kind, value, time)
+            ).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds a history row when the custom status differs from the last one recorded for the user.
+async fn record_custom_status(conn: &libsql::Connection, guild_id: u64, user_id: u64, time: u64, status: Option<CustomStatus>) -> Result<(), libsql::Error> {
+    let mut rows = conn.query(
+        "SELECT text, emoji FROM custom_statuses WHERE guild_id = ?1 AND user_id = ?2 ORDER BY time DESC, id DESC LIMIT 1",
+        [guild_id, user_id]
+    ).await?;
+
+    let latest = match rows.next().await? {
+        Some(row) => {
+            let text: Option<String> = row.get(0)?;
+            let emoji: Option<String> = row.get(1)?;
+            if text.is_none() && emoji.is_none() { None } else { Some(CustomStatus { text, emoji }) }
+        },
+        None => None
+    };
+
+    if latest == status {
+        return Ok(());
+    }
+
+    let (text, emoji) = match status {
+        Some(status) => (status.text, status.emoji),
+        None => (None, None)
+    };
+    conn.execute(
+        "INSERT INTO custom_statuses (guild_id, user_id, time, text, emoji) VALUES (?1, ?2, ?3, ?4, ?5)",
+        (guild_id, user_id, time, text, emoji)
+    ).await?;
+    Ok(())
+}
+
+/// Each job runs in its own savepoint so a failing job leaves nothing half written behind.
+async fn write_job(conn: &libsql::Connection, job: WriteJob, job_id: Option<&str>) -> Result<(), libsql::Error> {
+    conn.execute("SAVEPOINT write_job", ()).await?;
+
+    let result = match job {
+        WriteJob::Presence(job) => write_presence(conn, *job, job_id).await,
+        WriteJob::Voice(event) => write_voice_event(conn, event, job_id).await,
+        WriteJob::Message(event) => write_message_event(conn, event, job_id).await,
+        WriteJob::Member(event) => write_member_event(conn, event, job_id).await,
+        WriteJob::Identity(job) => write_identity(conn, job, job_id).await
+    };
+
+    if result.is_err() {
+        conn.execute("ROLLBACK TO write_job", ()).await?;
+    }
+    conn.execute("RELEASE write_job", ()).await?;
+    result
+}
+
+/// `job_id` is only set for jobs replayed from the spool. A job that was already written inserts nothing.
+/// Any failure fails the whole job, so its savepoint is rolled back and it is spooled instead of half written.
+/// Spooled jobs arrive after newer live ones, so only a presence at least as new as the user's latest row
+/// moves sessions, plays and custom statuses. Older ones only add their raw row.
+async fn write_presence(conn: &libsql::Connection, job: PresenceJob, job_id: Option<&str>) -> Result<(), libsql::Error> {
+    let sessions = session_values(&job);
+    let is_offline = job.status == "offline";
+
+    let mut rows = conn.query(
+        "SELECT MAX(time) FROM tracking_data WHERE user_id = ?1 AND guild_id = ?2",
+        [job.user_id, job.guild_id]
+    ).await?;
+    let latest: Option<u64> = match rows.next().await? {
+        Some(row) => row.get(0)?,
+        None => None
+    };
+    let is_latest = latest.is_none_or(|latest| job.time >= latest);
+
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO tracking_data (guild_id, user_id, time, status, activity, activity_description, desktop_status, mobile_status, web_status, source, job_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        (job.guild_id, job.user_id, job.time, job.status, job.activity, job.activity_description, job.desktop_status, job.mobile_status, job.web_status, job.source, job_id)
+    ).await?;
+    if inserted == 0 {
+        println!("Presence job {:?} was already written", job_id);
+        return Ok(());
+    }
+
+    let tracking_id = conn.last_insert_rowid();
+
+    for activity in job.activities {
+        conn.execute(
+            "INSERT INTO presence_activities (
+                tracking_id, position, name, details, kind, state, started_at, ends_at,
+                party_size, party_max, application_id, large_text, small_text, url, emoji
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+            (
+                tracking_id, activity.position, activity.name, activity.details, activity.kind, activity.state,
+                activity.started_at.map(|t| t as i64), activity.ends_at.map(|t| t as i64),
+                activity.party_size, activity.party_max, activity.application_id.map(|id| id as i64),
+                activity.large_text, activity.small_text, activity.url, activity.emoji
+            )
+        ).await?;
+    }
+
+    if !is_latest {
+        return Ok(());
+    }
+
+    update_sessions(conn, job.guild_id, job.user_id, job.time, sessions).await?;
+
+    if let Some(play) = job.spotify {
+        record_spotify_play(conn, play).await?;
+    }
+
+    // Offline presences carry no activities, which says nothing about whether the custom status was cleared
+    if !is_offline {
+        record_custom_status(conn, job.guild_id, job.user_id, job.time, job.custom_status).await?;
+    }
+
+    Ok(())
+}
+
+async fn write_voice_event(conn: &libsql::Connection, event: VoiceEvent, job_id: Option<&str>) -> Result<(), libsql::Error> {
+    conn.execute(
+        "INSERT OR IGNORE INTO voice_events (guild_id, user_id, time, kind, channel_id, channel_name, previous_channel_id, previous_channel_name, job_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        (
+            event.guild_id, event.user_id, event.time, event.kind,
+            event.channel_id.map(|id| id as i64), event.channel_name,
+            event.previous_channel_id.map(|id| id as i64), event.previous_channel_name, job_id
+        )
+    ).await?;
+    Ok(())
+}
+
+async fn write_message_event(conn: &libsql::Connection, event: MessageEvent, job_id: Option<&str>) -> Result<(), libsql::Error> {
+    conn.execute(
+        "INSERT OR IGNORE INTO message_events (guild_id, user_id, channel_id, channel_name, time, length, attachments, is_reply, in_thread, job_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        (
+            event.guild_id, event.user_id, event.channel_id, event.channel_name, event.time,
+            event.length, event.attachments, event.is_reply, event.in_thread, job_id
+        )
+    ).await?;
+    Ok(())
+}
+
+async fn write_member_event(conn: &libsql::Connection, event: MemberEvent, job_id: Option<&str>) -> Result<(), libsql::Error> {
+    conn.execute(
+        "INSERT OR IGNORE INTO member_events (guild_id, user_id, time, kind, nick_before, nick_after, roles_added, roles_removed, job_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        (
+            event.guild_id, event.user_id, event.time, event.kind, event.nick_before, event.nick_after,
+            event.roles_added.join("\n"), event.roles_removed.join("\n"), job_id
+        )
+    ).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn no_gaps_inside_one_period() {
+        assert_eq!(coverage_gaps(vec!((0, 1000)), 100, 900, 2000), vec!());
+    }
+
+    #[test]
+    fn gaps_between_periods_and_before_the_first() {
+        let periods = vec!((200, 300), (500, 1000));
+        assert_eq!(coverage_gaps(periods, 100, 900, 2000), vec!((100, 200), (300, 500)));
+    }
+
+    #[test]
+    fn overlapping_periods_leave_no_gap() {
+        let periods = vec!((0, 400), (100, 200), (300, 1000));
+        assert_eq!(coverage_gaps(periods, 100, 900, 2000), vec!());
+    }
+
+    #[test]
+    fn gap_after_the_last_period_ends_now() {
+        assert_eq!(coverage_gaps(vec!((0, 300)), 100, 900, 500), vec!((300, 500)));
+        assert_eq!(coverage_gaps(vec!((0, 300)), 100, 900, 2000), vec!((300, 900)));
+    }
+
+    #[test]
+    fn no_periods_is_one_gap() {
+        assert_eq!(coverage_gaps(vec!(), 100, 900, 2000), vec!((100, 900)));
+        assert_eq!(coverage_gaps(vec!(), 100, 900, 500), vec!((100, 500)));
+    }
+
+    #[test]
+    fn nothing_expires_without_cutoffs() {
+        let cutoffs = Cutoffs { default: None, guilds: vec!((1, None)) };
+        assert_eq!(expired_condition(&cutoffs), "0");
+    }
+
+    #[test]
+    fn default_cutoff_applies_to_every_guild() {
+        let cutoffs = Cutoffs { default: Some(100), guilds: vec!() };
+        assert_eq!(expired_condition(&cutoffs), "time < 100");
+    }
+
+    #[test]
+    fn guild_cutoffs_replace_the_default() {
+        let cutoffs = Cutoffs { default: Some(100), guilds: vec!((1, Some(50)), (2, None)) };
+        assert_eq!(
+            expired_condition(&cutoffs),
+            "(guild_id = 1 AND time < 50) OR ((guild_id IS NULL OR guild_id NOT IN (1, 2)) AND time < 100)"
+        );
+    }
+
+    #[test]
+    fn sessions_of_one_user_in_several_guilds_count_once() {
+        let sessions = vec!(
+            (1, String::from("Game"), 100, 300),
+            (1, String::from("Game"), 100, 300),
+            (1, String::from("Game"), 200, 400),
+            (1, String::from("Game"), 500, 600),
+            (2, String::from("Game"), 100, 300),
+            (1, String::from("Other"), 100, 150)
+        );
+        assert_eq!(activity_totals(sessions, 10), vec!((String::from("Game"), 600), (String::from("Other"), 50)));
+    }
+
+    #[test]
+    fn like_wildcards_match_literally() {
+        assert_eq!(contains_pattern("100%_done\\"), "%100\\%\\_done\\\\%");
+    }
+
+    #[test]
+    fn dashboard_filters_are_bound() {
+        let filter = DataFilter {
+            guild_id: Some("1"),
+            status: Some("online' OR 1=1 --"),
+            application_id: Some("not a number"),
+            custom_status: Some("50%"),
+            ..Default::default()
+        };
+        let (query, params) = data_query(&1, &filter);
+
+        assert!(!query.contains("OR 1=1"));
+        assert!(query.contains("guild_id = ?1") && query.contains("status = ?2"));
+        assert!(query.contains("pa.application_id = ?3") && query.contains("pa.state LIKE ?4"));
+        assert_eq!(params, vec!(
+            libsql::Value::Integer(1),
+            libsql::Value::Text(String::from("online' OR 1=1 --")),
+            libsql::Value::Null,
+            libsql::Value::Text(String::from("%50\\%%"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_failing_job_leaves_nothing_behind() {
+        let db = Database::open(":memory:").await.unwrap();
+        // The presence row goes in before its activities, which now fail
+        db.conn.execute("DROP TABLE presence_activities", ()).await.unwrap();
+
+        let unwritten = db.write_batch(vec!(
+            WriteJob::Presence(Box::new(fixtures::presence(2, 100, "online", Some("Game")))),
+            WriteJob::Message(fixtures::message(2, 100))
+        )).await;
+
+        assert!(matches!(unwritten.as_slice(), [WriteJob::Presence(_)]));
+        assert!(db.get_data(&1, &DataFilter::default()).await.is_empty());
+        assert!(db.get_sessions(None, None, 10).await.is_empty());
+        assert_eq!(db.get_message_counts_by_user(None, None, 10).await, vec!((2, 1)));
+    }
+}
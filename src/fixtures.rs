@@ -0,0 +1,87 @@
+use crate::database::{ActivityRecord, IdentityJob, MessageEvent, PresenceJob, SpotifyPlay, UserIdentity};
+
+/// The guild every fixture belongs to.
+pub const GUILD: u64 = 1;
+
+/// A live presence update in `GUILD`, playing `activity` when there is one.
+pub fn presence(user_id: u64, time: u64, status: &str, activity: Option<&str>) -> PresenceJob {
+    PresenceJob {
+        guild_id: GUILD,
+        user_id,
+        time,
+        status: String::from(status),
+        activity: String::from(activity.unwrap_or("Unknown")),
+        activity_description: String::from("Unknown"),
+        desktop_status: Some(String::from(status)),
+        mobile_status: None,
+        web_status: None,
+        activities: activity.into_iter().map(|name| playing(0, name)).collect(),
+        spotify: None,
+        custom_status: None,
+        source: String::from("update")
+    }
+}
+
+/// A Playing activity without any metadata.
+pub fn playing(position: u32, name: &str) -> ActivityRecord {
+    ActivityRecord {
+        position,
+        name: String::from(name),
+        details: None,
+        kind: String::from("Playing"),
+        state: None,
+        started_at: None,
+        ends_at: None,
+        party_size: None,
+        party_max: None,
+        application_id: None,
+        large_text: None,
+        small_text: None,
+        url: None,
+        emoji: None
+    }
+}
+
+/// `user_id` listening to `track_id` on Spotify in `GUILD`.
+pub fn spotify_play(user_id: u64, track_id: &str, started_at: u64, ends_at: Option<u64>) -> SpotifyPlay {
+    SpotifyPlay {
+        guild_id: GUILD,
+        user_id,
+        track_id: String::from(track_id),
+        title: format!("Title of {track_id}"),
+        artist: String::from("Artist"),
+        album: None,
+        started_at,
+        ends_at
+    }
+}
+
+/// A message in the general channel of `GUILD`, recorded without MESSAGE_CONTENT.
+pub fn message(user_id: u64, time: u64) -> MessageEvent {
+    MessageEvent {
+        guild_id: GUILD,
+        user_id,
+        channel_id: 5,
+        channel_name: Some(String::from("general")),
+        time,
+        length: None,
+        attachments: None,
+        is_reply: false,
+        in_thread: false
+    }
+}
+
+/// `user_id` seen in `GUILD` with nothing but a username.
+pub fn identity(user_id: u64, username: &str, time: u64) -> IdentityJob {
+    IdentityJob {
+        identity: UserIdentity {
+            guild_id: GUILD,
+            user_id,
+            username: String::from(username),
+            global_name: None,
+            nickname: None,
+            avatar: None
+        },
+        time
+    }
+}
@@ -1,23 +1,201 @@
+mod changes;
 mod database;
+#[cfg(test)]
+mod fixtures;
+mod memory;
+mod migrations;
+mod retention;
+mod scope;
+mod spool;
+mod store;
 mod webserver;
 
 use std::env;
-use std::time::{SystemTime, UNIX_EPOCH};
-use log::info;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::error;
 use dotenv::dotenv;
 use poise::serenity_prelude as serenity;
 use poise::serenity_prelude::*;
 use std::sync::Arc;
+use store::Store;
+use tokio::sync::Notify;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::TrySendError;
 use rand::{distr::Alphanumeric, Rng};
-use std::thread;
 
 struct Data {
-    key: String
+    key: String,
+    store: Arc<dyn Store>,
+    scope: Arc<scope::ScopeCache>
 }
 
 struct Handler {
-    tx:Sender<database::WriteJob>
+    tx:Sender<database::WriteJob>,
+    store: Arc<dyn Store>,
+    spool: Arc<spool::Spool>,
+    scope: Arc<scope::ScopeCache>,
+    changes: changes::ChangeDetector,
+    identities: changes::IdentityTracker
+}
+
+const COVERAGE_HEARTBEAT_SECONDS: u64 = 30;
+const WRITER_SHUTDOWN_SECONDS: u64 = 30;
+
+impl Handler {
+    /// Never waits on a full write queue. Whatever does not fit goes to the spool and is replayed later.
+    fn enqueue(&self, job: database::WriteJob) {
+        match self.tx.try_send(job) {
+            Ok(()) => {},
+            Err(TrySendError::Full(job)) => {
+                println!("Write queue is full, spooling job");
+                self.spool.append(vec!(job));
+            },
+            Err(TrySendError::Closed(job)) => {
+                error!("Write queue is closed, spooling job");
+                self.spool.append(vec!(job));
+            }
+        }
+    }
+
+    /// A job for the identity, or `None` when the user still looks like the last time they were queued.
+    fn identity_job(&self, identity: database::UserIdentity, time: u64) -> Option<database::WriteJob> {
+        if !self.identities.is_change(&identity) {
+            return None;
+        }
+        Some(database::WriteJob::Identity(database::IdentityJob { identity, time }))
+    }
+
+    /// Queues the identity when it changed.
+    fn record_identity(&self, identity: database::UserIdentity) {
+        if let Some(job) = self.identity_job(identity, unix_now()) {
+            self.enqueue(job);
+        }
+    }
+
+    /// Opt outs and the guild's scope rules decide whether anything about a member is recorded.
+    /// `is_bot` and `roles` come from the event when it carries them, otherwise from the cache.
+    async fn is_tracked(&self, ctx: &serenity::Context, guild_id: GuildId, user_id: UserId, is_bot: Option<bool>, roles: Option<Vec<u64>>) -> Result<bool, Error> {
+        if self.scope.is_opted_out(self.store.as_ref(), user_id.get()).await? {
+            return Ok(false);
+        }
+
+        let cached = ctx.cache.guild(guild_id).and_then(|guild| {
+            guild.members.get(&user_id).map(|member| (member.user.bot, role_ids(&member.roles)))
+        });
+        let (cached_bot, cached_roles) = match cached {
+            Some((bot, roles)) => (Some(bot), Some(roles)),
+            None => (None, None)
+        };
+        let is_bot = is_bot.or(cached_bot).unwrap_or(false);
+        let roles = roles.or(cached_roles);
+
+        Ok(self.scope.tracking_rules(self.store.as_ref(), guild_id.get()).await?.allows(user_id.get(), is_bot, roles.as_deref()))
+    }
+
+    /// Whether an event of the member is recorded at all: the guild is enabled and the member is tracked in it.
+    /// Nothing is recorded when either could not be looked up.
+    async fn should_record(&self, ctx: &serenity::Context, guild_id: GuildId, user_id: UserId, is_bot: Option<bool>, roles: Option<Vec<u64>>) -> bool {
+        let tracked = async {
+            Ok::<bool, Error>(self.scope.is_guild_enabled(self.store.as_ref(), guild_id.get()).await? && self.is_tracked(ctx, guild_id, user_id, is_bot, roles).await?)
+        };
+        match tracked.await {
+            Ok(tracked) => tracked,
+            Err(e) => {
+                error!("Could not look up whether {} is tracked in {}, ignoring the event {}", user_id, guild_id, e);
+                false
+            }
+        }
+    }
+
+    async fn record_presence(&self, ctx: &serenity::Context, new_data: Presence) -> Result<(), Error> {
+        let guild_id = new_data.guild_id.ok_or("presence update without a guild")?;
+        if !self.scope.is_guild_enabled(self.store.as_ref(), guild_id.get()).await? {
+            println!("Ignoring status update. Guild {} is not tracked", guild_id);
+            return Ok(());
+        }
+
+        // Opted out and out of scope users must not reach the write queue at all
+        if !self.is_tracked(ctx, guild_id, new_data.user.id, new_data.user.bot, None).await? {
+            return Ok(());
+        }
+
+        let resolved = resolve_presence_user(ctx, self.store.as_ref(), guild_id, &new_data.user).await;
+        println!("Presence update for {} arrived", resolved.name);
+
+        if let Some(identity) = resolved.identity {
+            self.record_identity(identity);
+        }
+
+        let job = presence_job(guild_id.get(), &new_data, unix_now(), "update");
+
+        if !self.changes.is_change(&job) {
+            println!("Presence for {} did not change, skipping", resolved.name);
+            return Ok(());
+        }
+
+        self.enqueue(database::WriteJob::Presence(Box::new(job)));
+        Ok(())
+    }
+}
+
+/// What is known about the user behind a presence update, which may only carry an id.
+struct ResolvedUser {
+    name: String,
+    /// Only set when the source has the full user, so partial data never overwrites the history
+    identity: Option<database::UserIdentity>
+}
+
+/// Fills in a partial presence user from the cache, then the `users` table, then the API.
+/// Falls back to the id so the presence is still recorded when every lookup fails.
+async fn resolve_presence_user(ctx: &serenity::Context, store: &dyn Store, guild_id: GuildId, user: &PresenceUser) -> ResolvedUser {
+    let cached = ctx.cache
+        .guild(guild_id)
+        .and_then(|guild| guild.members.get(&user.id).map(member_identity));
+    if let Some(identity) = cached {
+        return ResolvedUser { name: identity.username.clone(), identity: Some(identity) };
+    }
+
+    if let Some(name) = &user.name {
+        return ResolvedUser {
+            name: name.clone(),
+            identity: Some(database::UserIdentity {
+                guild_id: guild_id.get(),
+                user_id: user.id.get(),
+                username: name.clone(),
+                global_name: None,
+                nickname: None,
+                avatar: user.avatar.map(|hash| hash.to_string())
+            })
+        };
+    }
+
+    if let Some(name) = ctx.cache.user(user.id).map(|cached| cached.name.clone()) {
+        return ResolvedUser { name, identity: None };
+    }
+
+    match store.get_username(user.id.get()).await {
+        Ok(Some(name)) => return ResolvedUser { name, identity: None },
+        Ok(None) => {},
+        Err(e) => error!("Failed to look up username for {} {}", user.id, e)
+    }
+
+    match ctx.http.get_user(user.id).await {
+        Ok(fetched) => ResolvedUser {
+            name: fetched.name.clone(),
+            identity: Some(database::UserIdentity {
+                guild_id: guild_id.get(),
+                user_id: fetched.id.get(),
+                username: fetched.name.clone(),
+                global_name: fetched.global_name.clone(),
+                nickname: None,
+                avatar: fetched.avatar.map(|hash| hash.to_string())
+            })
+        },
+        Err(e) => {
+            error!("Failed to fetch user {} {}", user.id, e);
+            ResolvedUser { name: user.id.to_string(), identity: None }
+        }
+    }
 }
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
@@ -26,49 +204,457 @@ type Context<'a> = poise::Context<'a, Data, Error>;
 
 #[serenity::async_trait]
 impl EventHandler for Handler {
-    async fn ready(&self, _ctx: serenity::Context, ready: Ready) {
+    async fn ready(&self, ctx: serenity::Context, ready: Ready) {
         println!("Bot logged in to {}", ready.user.name);
+        self.store.open_coverage(ctx.shard_id.0, "ready", unix_now()).await;
+    }
+
+    async fn resume(&self, ctx: serenity::Context, _event: ResumedEvent) {
+        println!("Shard {} resumed", ctx.shard_id.0);
+        self.store.open_coverage(ctx.shard_id.0, "resume", unix_now()).await;
+    }
+
+    async fn shard_stage_update(&self, _ctx: serenity::Context, event: ShardStageUpdateEvent) {
+        if event.old == ConnectionStage::Connected && event.new != ConnectionStage::Connected {
+            println!("Shard {} lost its connection ({:?})", event.shard_id.0, event.new);
+            self.store.close_coverage(Some(event.shard_id.0), "disconnected", unix_now()).await;
+        }
     }
 
     async fn guild_create(&self, _ctx: serenity::Context, guild: Guild, _is_new: Option<bool>) {
+        self.store.register_guild(guild.id.get(), &guild.name).await;
         println!("Guild {} registered", guild.name);
+
+        match self.scope.is_guild_enabled(self.store.as_ref(), guild.id.get()).await {
+            Ok(true) => {},
+            Ok(false) => return,
+            Err(e) => {
+                error!("Could not look up whether {} is enabled, skipping its connect snapshot {}", guild.name, e);
+                return;
+            }
+        }
+
+        // Give everyone a known state from the moment we connect instead of waiting for their next update
+        let time = unix_now();
+        let rules = match self.scope.tracking_rules(self.store.as_ref(), guild.id.get()).await {
+            Ok(rules) => rules,
+            Err(e) => {
+                error!("Could not load the tracking rules of {}, skipping its connect snapshot {}", guild.name, e);
+                return;
+            }
+        };
+        let opted_out = match self.scope.opted_out(self.store.as_ref()).await {
+            Ok(opted_out) => opted_out,
+            Err(e) => {
+                error!("Could not load opt outs, skipping the connect snapshot of {} {}", guild.name, e);
+                return;
+            }
+        };
+
+        let mut jobs: Vec<database::WriteJob> = vec!();
+        for (user_id, member) in guild.members.iter() {
+            if !rules.allows(user_id.get(), member.user.bot, Some(&role_ids(&member.roles))) || opted_out.contains(&user_id.get()) {
+                continue;
+            }
+
+            jobs.extend(self.identity_job(member_identity(member), time));
+
+            let job = match guild.presences.get(user_id) {
+                Some(presence) => presence_job(guild.id.get(), presence, time, "snapshot"),
+                None => offline_at_connect_job(guild.id.get(), user_id.get(), time)
+            };
+
+            // Prime the change detector so the next identical update is not written again
+            self.changes.is_change(&job);
+            jobs.push(database::WriteJob::Presence(Box::new(job)));
+        }
+
+        // Large guilds would fill the write queue, so the snapshot is written in batches of its own
+        let size = jobs.len();
+        let batch_size = store::batch_size();
+        while !jobs.is_empty() {
+            let batch: Vec<database::WriteJob> = jobs.drain(..batch_size.min(jobs.len())).collect();
+            let unwritten = self.store.write_batch(batch).await;
+            if !unwritten.is_empty() {
+                self.spool.append(unwritten);
+            }
+        }
+        println!("Wrote connect snapshot of {} jobs for {}", size, guild.name);
     }
 
-    async fn presence_update(&self, _ctx: serenity::Context, new_data: Presence) {
-        if &new_data.guild_id.unwrap().get().to_string() != (&env::var("SCAN_GUILD").unwrap()) {
-            println!("Ignoring status update. Wrong guild {}", &new_data.guild_id.unwrap().get().to_string());
+    async fn presence_update(&self, ctx: serenity::Context, new_data: Presence) {
+        let user_id = new_data.user.id;
+        if let Err(e) = self.record_presence(&ctx, new_data).await {
+            error!("Failed to record presence update for {} {}", user_id, e);
+        }
+    }
+
+    async fn message(&self, ctx: serenity::Context, new_message: Message) {
+        let Some(guild_id) = new_message.guild_id else {
+            return;
+        };
+        if new_message.author.id == ctx.cache.current_user().id {
+            return;
+        }
+        if !self.should_record(&ctx, guild_id, new_message.author.id, Some(new_message.author.bot), new_message.member.as_ref().map(|member| role_ids(&member.roles))).await {
             return;
         }
 
-        let username =  &new_data.user.name.unwrap();
-        println!("Presence update for {} arrived", username);
-
-        database::associate_usermame(new_data.user.id.get(),username).await;
-        
-        let activity = new_data
-            .activities
-            .first()
-            .map(|a| a.name.as_str())
-            .unwrap_or("Unknown");
-        
-        let activity_description = new_data
-            .activities
-            .first()
-            .and_then(|a| a.details.as_deref())
-            .unwrap_or("Unknown");
-
-        let job = database::WriteJob {
-            user_id: new_data.user.id.get(),
+        let identity = database::UserIdentity {
+            guild_id: guild_id.get(),
+            user_id: new_message.author.id.get(),
+            username: new_message.author.name.clone(),
+            global_name: new_message.author.global_name.clone(),
+            nickname: new_message.member.as_ref().and_then(|member| member.nick.clone()),
+            avatar: new_message.author.avatar.map(|hash| hash.to_string())
+        };
+        self.record_identity(identity);
+
+        let (channel_name, in_thread) = match ctx.cache.guild(guild_id) {
+            Some(guild) => match guild.threads.iter().find(|thread| thread.id == new_message.channel_id) {
+                Some(thread) => (Some(thread.name.clone()), true),
+                None => (guild.channels.get(&new_message.channel_id).map(|channel| channel.name.clone()), false)
+            },
+            None => (None, false)
+        };
+
+        let event = database::MessageEvent {
+            guild_id: guild_id.get(),
+            user_id: new_message.author.id.get(),
+            channel_id: new_message.channel_id.get(),
+            channel_name,
+            time: new_message.timestamp.unix_timestamp() as u64,
+            length: log_message_length().then(|| new_message.content.chars().count() as u32),
+            // Without MESSAGE_CONTENT Discord sends no attachments, so a count of 0 would be a guess
+            attachments: log_message_length().then_some(new_message.attachments.len() as u32),
+            is_reply: new_message.kind == MessageType::InlineReply,
+            in_thread
+        };
+
+        self.enqueue(database::WriteJob::Message(event));
+    }
+
+    async fn guild_member_addition(&self, ctx: serenity::Context, new_member: Member) {
+        if !self.should_record(&ctx, new_member.guild_id, new_member.user.id, Some(new_member.user.bot), Some(role_ids(&new_member.roles))).await {
+            return;
+        }
+
+        self.record_identity(member_identity(&new_member));
+        println!("Member {} joined", new_member.user.name);
+
+        let event = database::MemberEvent {
+            guild_id: new_member.guild_id.get(),
+            user_id: new_member.user.id.get(),
             time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
-            status: String::from(new_data.status.name()),
-            activity: String::from(activity),
-            activity_description: String::from(activity_description)
+            kind: String::from("join"),
+            nick_before: None,
+            nick_after: new_member.nick.clone(),
+            roles_added: vec!(),
+            roles_removed: vec!()
         };
+        self.enqueue(database::WriteJob::Member(event));
+    }
+
+    async fn guild_member_removal(&self, ctx: serenity::Context, guild_id: GuildId, user: User, member_data_if_available: Option<Member>) {
+        // The member has already left the cache, so their roles only come with the event
+        let roles = member_data_if_available.as_ref().map(|member| role_ids(&member.roles));
+        if !self.should_record(&ctx, guild_id, user.id, Some(user.bot), roles).await {
+            return;
+        }
+
+        println!("Member {} left", user.name);
 
-        self.tx.send(job).await.unwrap();
+        let event = database::MemberEvent {
+            guild_id: guild_id.get(),
+            user_id: user.id.get(),
+            time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            kind: String::from("leave"),
+            nick_before: member_data_if_available.and_then(|member| member.nick),
+            nick_after: None,
+            roles_added: vec!(),
+            roles_removed: vec!()
+        };
+        self.enqueue(database::WriteJob::Member(event));
     }
+
+    async fn guild_member_update(&self, ctx: serenity::Context, old_if_available: Option<Member>, new: Option<Member>, event: GuildMemberUpdateEvent) {
+        if !self.should_record(&ctx, event.guild_id, event.user.id, Some(event.user.bot), Some(role_ids(&event.roles))).await {
+            return;
+        }
+
+        if let Some(member) = &new {
+            self.record_identity(member_identity(member));
+        }
+
+        // Without the previous member from the cache there is nothing to compare against
+        let Some(old) = old_if_available else {
+            println!("No cached member for {}, skipping member update", event.user.name);
+            return;
+        };
+
+        let role_name = |role_id: &RoleId| -> String {
+            ctx.cache
+                .guild(event.guild_id)
+                .and_then(|guild| guild.roles.get(role_id).map(|role| role.name.clone()))
+                .unwrap_or(role_id.get().to_string())
+        };
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut events: Vec<database::MemberEvent> = vec!();
+
+        if old.nick != event.nick {
+            events.push(database::MemberEvent {
+                guild_id: event.guild_id.get(),
+                user_id: event.user.id.get(),
+                time,
+                kind: String::from("nickname"),
+                nick_before: old.nick.clone(),
+                nick_after: event.nick.clone(),
+                roles_added: vec!(),
+                roles_removed: vec!()
+            });
+        }
+
+        let roles_added: Vec<String> = event.roles.iter().filter(|role| !old.roles.contains(role)).map(role_name).collect();
+        let roles_removed: Vec<String> = old.roles.iter().filter(|role| !event.roles.contains(role)).map(role_name).collect();
+        if !roles_added.is_empty() || !roles_removed.is_empty() {
+            events.push(database::MemberEvent {
+                guild_id: event.guild_id.get(),
+                user_id: event.user.id.get(),
+                time,
+                kind: String::from("roles"),
+                nick_before: None,
+                nick_after: None,
+                roles_added,
+                roles_removed
+            });
+        }
+
+        for member_event in events {
+            println!("Member {} changed {}", event.user.name, member_event.kind);
+            self.enqueue(database::WriteJob::Member(member_event));
+        }
+    }
+
+    async fn voice_state_update(&self, ctx: serenity::Context, old: Option<VoiceState>, new: VoiceState) {
+        let Some(guild_id) = new.guild_id else {
+            return;
+        };
+        if !self.should_record(&ctx, guild_id, new.user_id, new.member.as_ref().map(|member| member.user.bot), new.member.as_ref().map(|member| role_ids(&member.roles))).await {
+            return;
+        }
+
+        if let Some(member) = &new.member {
+            self.record_identity(member_identity(member));
+        }
+
+        let channel_name = |channel_id: Option<ChannelId>| -> Option<String> {
+            let channel_id = channel_id?;
+            let guild = ctx.cache.guild(guild_id)?;
+            guild.channels.get(&channel_id).map(|channel| channel.name.clone())
+        };
+
+        let previous_channel = old.as_ref().and_then(|o| o.channel_id);
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        for kind in voice_changes(old.as_ref(), &new) {
+            println!("Voice event {} for {}", kind, new.user_id.get());
+            let event = database::VoiceEvent {
+                guild_id: guild_id.get(),
+                user_id: new.user_id.get(),
+                time,
+                kind: String::from(kind),
+                channel_id: new.channel_id.map(|id| id.get()),
+                channel_name: channel_name(new.channel_id),
+                previous_channel_id: previous_channel.map(|id| id.get()),
+                previous_channel_name: channel_name(previous_channel)
+            };
+
+            self.enqueue(database::WriteJob::Voice(event));
+        }
+    }
+}
+
+/// Message lengths and attachment counts need the privileged MESSAGE_CONTENT intent, so they are only recorded when explicitly enabled.
+fn log_message_length() -> bool {
+    env::var("LOG_MESSAGE_LENGTH").is_ok_and(|value| value == "true" || value == "1")
+}
+
+/// Names every change between two voice states. Without a cached old state a connected user counts as having joined.
+fn voice_changes(old: Option<&VoiceState>, new: &VoiceState) -> Vec<&'static str> {
+    let mut changes: Vec<&'static str> = vec!();
+
+    let old_channel = old.and_then(|o| o.channel_id);
+    match (old_channel, new.channel_id) {
+        (None, Some(_)) => changes.push("join"),
+        (Some(_), None) => changes.push("leave"),
+        (Some(before), Some(after)) if before != after => changes.push("move"),
+        _ => {}
+    }
+
+    let Some(old) = old else {
+        return changes;
+    };
+    if old.channel_id.is_none() || new.channel_id.is_none() {
+        return changes;
+    }
+
+    let toggles = [
+        (old.self_mute, new.self_mute, "self_mute", "self_unmute"),
+        (old.self_deaf, new.self_deaf, "self_deafen", "self_undeafen"),
+        (old.mute, new.mute, "server_mute", "server_unmute"),
+        (old.deaf, new.deaf, "server_deafen", "server_undeafen"),
+        (old.self_stream.unwrap_or(false), new.self_stream.unwrap_or(false), "stream_start", "stream_stop"),
+        (old.self_video, new.self_video, "video_start", "video_stop")
+    ];
+    for (before, after, on, off) in toggles {
+        if before != after {
+            changes.push(if after { on } else { off });
+        }
+    }
+
+    changes
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn role_ids(roles: &[RoleId]) -> Vec<u64> {
+    roles.iter().map(|role| role.get()).collect()
 }
 
+fn member_identity(member: &Member) -> database::UserIdentity {
+    database::UserIdentity {
+        guild_id: member.guild_id.get(),
+        user_id: member.user.id.get(),
+        username: member.user.name.clone(),
+        global_name: member.user.global_name.clone(),
+        nickname: member.nick.clone(),
+        avatar: member.avatar.or(member.user.avatar).map(|hash| hash.to_string())
+    }
+}
+
+/// `source` tells live updates apart from the snapshot written when connecting to a guild.
+fn presence_job(guild_id: u64, presence: &Presence, time: u64, source: &str) -> database::PresenceJob {
+    let activity = presence
+        .activities
+        .first()
+        .map(|a| a.name.as_str())
+        .unwrap_or("Unknown");
+    
+    // Custom statuses keep their text in `state` rather than `details`
+    let activity_description = presence
+        .activities
+        .first()
+        .and_then(|a| if a.kind == ActivityType::Custom { a.state.as_deref() } else { a.details.as_deref() })
+        .unwrap_or("Unknown");
+
+    let activities = presence
+        .activities
+        .iter()
+        .enumerate()
+        .map(|(position, a)| activity_record(position as u32, a))
+        .collect();
+
+    let client_status = presence.client_status.as_ref();
+
+    database::PresenceJob {
+        guild_id,
+        user_id: presence.user.id.get(),
+        time,
+        status: String::from(presence.status.name()),
+        activity: String::from(activity),
+        activity_description: String::from(activity_description),
+        desktop_status: client_status.and_then(|c| c.desktop).map(|s| String::from(s.name())),
+        mobile_status: client_status.and_then(|c| c.mobile).map(|s| String::from(s.name())),
+        web_status: client_status.and_then(|c| c.web).map(|s| String::from(s.name())),
+        activities,
+        spotify: presence.activities.iter().find_map(|a| spotify_play(guild_id, presence.user.id.get(), a)),
+        custom_status: presence.activities.iter().find(|a| a.kind == ActivityType::Custom).map(|a| database::CustomStatus {
+            text: a.state.clone(),
+            emoji: a.emoji.as_ref().map(emoji_name)
+        }),
+        source: String::from(source)
+    }
+}
+
+/// Members without a presence in the guild payload are offline when the bot connects.
+fn offline_at_connect_job(guild_id: u64, user_id: u64, time: u64) -> database::PresenceJob {
+    database::PresenceJob {
+        guild_id,
+        user_id,
+        time,
+        status: String::from("offline"),
+        activity: String::from("Unknown"),
+        activity_description: String::from("Unknown"),
+        desktop_status: None,
+        mobile_status: None,
+        web_status: None,
+        activities: vec!(),
+        spotify: None,
+        custom_status: None,
+        source: String::from("offline_at_connect")
+    }
+}
+
+fn activity_kind_name(kind: ActivityType) -> &'static str {
+    match kind {
+        ActivityType::Playing => "Playing",
+        ActivityType::Streaming => "Streaming",
+        ActivityType::Listening => "Listening",
+        ActivityType::Watching => "Watching",
+        ActivityType::Custom => "Custom",
+        ActivityType::Competing => "Competing",
+        _ => "Unknown"
+    }
+}
+
+fn activity_record(position: u32, activity: &Activity) -> database::ActivityRecord {
+    database::ActivityRecord {
+        position,
+        name: activity.name.clone(),
+        details: activity.details.clone(),
+        kind: String::from(activity_kind_name(activity.kind)),
+        state: activity.state.clone(),
+        started_at: activity.timestamps.as_ref().and_then(|t| t.start),
+        ends_at: activity.timestamps.as_ref().and_then(|t| t.end),
+        party_size: activity.party.as_ref().and_then(|p| p.size).map(|size| size[0]),
+        party_max: activity.party.as_ref().and_then(|p| p.size).map(|size| size[1]),
+        application_id: activity.application_id.map(|id| id.get()),
+        large_text: activity.assets.as_ref().and_then(|a| a.large_text.clone()),
+        small_text: activity.assets.as_ref().and_then(|a| a.small_text.clone()),
+        url: activity.url.as_ref().map(|url| url.to_string()),
+        emoji: activity.emoji.as_ref().map(emoji_name)
+    }
+}
+
+/// Unicode emojis are stored as themselves, custom ones as `:name:`.
+fn emoji_name(emoji: &ActivityEmoji) -> String {
+    match emoji.id {
+        Some(_) => format!(":{}:", emoji.name),
+        None => emoji.name.clone()
+    }
+}
+
+/// Spotify reports the track in `details`, the artist in `state` and the album as the large asset text.
+fn spotify_play(guild_id: u64, user_id: u64, activity: &Activity) -> Option<database::SpotifyPlay> {
+    if activity.kind != ActivityType::Listening || activity.name != "Spotify" {
+        return None;
+    }
+
+    let timestamps = activity.timestamps.as_ref()?;
+    Some(database::SpotifyPlay {
+        guild_id,
+        user_id,
+        track_id: activity.sync_id.clone()?,
+        title: activity.details.clone().unwrap_or(String::from("Unknown")),
+        artist: activity.state.clone().unwrap_or(String::from("Unknown")),
+        album: activity.assets.as_ref().and_then(|a| a.large_text.clone()),
+        started_at: timestamps.start? / 1000,
+        ends_at: timestamps.end.map(|end| end / 1000)
+    })
+}
 
 #[poise::command(slash_command, prefix_command)]
 async fn ping(ctx: Context<'_>) -> Result<(), Error> {
@@ -76,27 +662,288 @@ async fn ping(ctx: Context<'_>) -> Result<(), Error> {
     Ok(())
 }
 
+fn is_admin(ctx: Context<'_>) -> bool {
+    format!("{}",ctx.author().id.get()) == env::var("ADMIN_ID").expect("Admin id not configured")
+}
+
 #[poise::command(slash_command, prefix_command, ephemeral)]
 async fn login(ctx: Context<'_>) -> Result<(), Error> {
-    if format!("{}",ctx.author().id.get()) != env::var("ADMIN_ID").expect("Admin id not configured") {
+    if !is_admin(ctx) {
+        ctx.say("You have to be admin to run this command.").await?;
+        return Ok(())
+    }
+
+    ctx.say(ctx.data().key.to_string()).await?;
+
+    Ok(())
+}
+
+/// Enable or disable presence tracking for the current server
+#[poise::command(slash_command, prefix_command, guild_only, ephemeral)]
+async fn tracking(ctx: Context<'_>, enabled: bool) -> Result<(), Error> {
+    if !is_admin(ctx) {
+        ctx.say("You have to be admin to run this command.").await?;
+        return Ok(())
+    }
+
+    let guild_id = ctx.guild_id().unwrap();
+    ctx.data().store.set_guild_enabled(guild_id.get(), enabled).await;
+    ctx.data().scope.invalidate_guild(guild_id.get());
+
+    if enabled {
+        ctx.say("Tracking enabled for this server.").await?;
+    } else {
+        ctx.say("Tracking disabled for this server.").await?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, poise::ChoiceParameter)]
+enum RuleKind {
+    #[name = "Include role"]
+    IncludeRole,
+    #[name = "Exclude role"]
+    ExcludeRole,
+    #[name = "Allow user"]
+    AllowUser,
+    #[name = "Deny user"]
+    DenyUser
+}
+
+impl RuleKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RuleKind::IncludeRole => "include_role",
+            RuleKind::ExcludeRole => "exclude_role",
+            RuleKind::AllowUser => "allow_user",
+            RuleKind::DenyUser => "deny_user"
+        }
+    }
+
+    /// Picks the role or the user depending on what the rule applies to
+    fn target(&self, role: Option<&serenity::Role>, user: Option<&serenity::User>) -> Option<u64> {
+        match self {
+            RuleKind::IncludeRole | RuleKind::ExcludeRole => role.map(|role| role.id.get()),
+            RuleKind::AllowUser | RuleKind::DenyUser => user.map(|user| user.id.get())
+        }
+    }
+}
+
+/// Choose which members of this server are recorded
+#[poise::command(slash_command, prefix_command, guild_only, ephemeral, subcommands("scope_add", "scope_remove", "scope_bots", "scope_list"))]
+async fn scope(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Add a role or user rule
+#[poise::command(slash_command, prefix_command, guild_only, ephemeral, rename = "add")]
+async fn scope_add(ctx: Context<'_>, kind: RuleKind, role: Option<serenity::Role>, user: Option<serenity::User>) -> Result<(), Error> {
+    if !is_admin(ctx) {
+        ctx.say("You have to be admin to run this command.").await?;
+        return Ok(())
+    }
+
+    let Some(target) = kind.target(role.as_ref(), user.as_ref()) else {
+        ctx.say("Role rules need a role and user rules need a user.").await?;
+        return Ok(())
+    };
+
+    ctx.data().store.add_tracking_rule(ctx.guild_id().unwrap().get(), kind.as_str(), target).await;
+    ctx.data().scope.invalidate_guild(ctx.guild_id().unwrap().get());
+    ctx.say(format!("Added {} rule for {}.", kind.as_str(), target)).await?;
+    Ok(())
+}
+
+/// Remove a role or user rule
+#[poise::command(slash_command, prefix_command, guild_only, ephemeral, rename = "remove")]
+async fn scope_remove(ctx: Context<'_>, kind: RuleKind, role: Option<serenity::Role>, user: Option<serenity::User>) -> Result<(), Error> {
+    if !is_admin(ctx) {
+        ctx.say("You have to be admin to run this command.").await?;
+        return Ok(())
+    }
+
+    let Some(target) = kind.target(role.as_ref(), user.as_ref()) else {
+        ctx.say("Role rules need a role and user rules need a user.").await?;
+        return Ok(())
+    };
+
+    let removed = ctx.data().store.remove_tracking_rule(ctx.guild_id().unwrap().get(), kind.as_str(), target).await;
+    ctx.data().scope.invalidate_guild(ctx.guild_id().unwrap().get());
+    if removed {
+        ctx.say(format!("Removed {} rule for {}.", kind.as_str(), target)).await?;
+    } else {
+        ctx.say("There was no such rule.").await?;
+    }
+    Ok(())
+}
+
+/// Record bot accounts or not
+#[poise::command(slash_command, prefix_command, guild_only, ephemeral, rename = "bots")]
+async fn scope_bots(ctx: Context<'_>, enabled: bool) -> Result<(), Error> {
+    if !is_admin(ctx) {
+        ctx.say("You have to be admin to run this command.").await?;
+        return Ok(())
+    }
+
+    ctx.data().store.set_track_bots(ctx.guild_id().unwrap().get(), enabled).await;
+    ctx.data().scope.invalidate_guild(ctx.guild_id().unwrap().get());
+
+    if enabled {
+        ctx.say("Bot accounts will be recorded.").await?;
+    } else {
+        ctx.say("Bot accounts will be ignored.").await?;
+    }
+    Ok(())
+}
+
+/// Show the rules for this server
+#[poise::command(slash_command, prefix_command, guild_only, ephemeral, rename = "list")]
+async fn scope_list(ctx: Context<'_>) -> Result<(), Error> {
+    if !is_admin(ctx) {
+        ctx.say("You have to be admin to run this command.").await?;
+        return Ok(())
+    }
+
+    let rules = ctx.data().store.get_tracking_rules(ctx.guild_id().unwrap().get()).await?;
+    let mentions = |ids: &Vec<u64>, prefix: &str| -> String {
+        if ids.is_empty() {
+            return String::from("none");
+        }
+        ids.iter().map(|id| format!("<@{prefix}{id}>")).collect::<Vec<String>>().join(", ")
+    };
+
+    ctx.say(format!(
+        "Bots: {}\nInclude roles: {}\nExclude roles: {}\nAllowed users: {}\nDenied users: {}",
+        if rules.track_bots { "recorded" } else { "ignored" },
+        mentions(&rules.include_roles, "&"),
+        mentions(&rules.exclude_roles, "&"),
+        mentions(&rules.allow_users, ""),
+        mentions(&rules.deny_users, "")
+    )).await?;
+    Ok(())
+}
+
+/// Set how many days raw events of this server are kept, 0 for forever. Leave empty for the default
+#[poise::command(slash_command, prefix_command, guild_only, ephemeral)]
+async fn retention(ctx: Context<'_>, days: Option<u64>) -> Result<(), Error> {
+    if !is_admin(ctx) {
         ctx.say("You have to be admin to run this command.").await?;
         return Ok(())
     }
 
-    ctx.say(format!("{}", ctx.data().key)).await?;
+    ctx.data().store.set_retention_days(ctx.guild_id().unwrap().get(), days).await;
+
+    match (days, retention::default_days()) {
+        (Some(0), _) | (None, None) => ctx.say("Raw events of this server are kept forever.").await?,
+        (Some(days), _) | (None, Some(days)) => ctx.say(format!("Raw events of this server are kept for {days} days.")).await?
+    };
+    Ok(())
+}
+
+/// Stop recording anything about you
+#[poise::command(slash_command, prefix_command, ephemeral)]
+async fn optout(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.data().store.set_opted_out(ctx.author().id.get(), true, unix_now()).await;
+    ctx.data().scope.invalidate_opt_outs();
+    ctx.say("You will no longer be recorded. Data collected so far is kept, run /forgetme to delete it.").await?;
+    Ok(())
+}
 
+/// Allow recording your activity again
+#[poise::command(slash_command, prefix_command, ephemeral)]
+async fn optin(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.data().store.set_opted_out(ctx.author().id.get(), false, unix_now()).await;
+    ctx.data().scope.invalidate_opt_outs();
+    ctx.say("You will be recorded again.").await?;
+    Ok(())
+}
+
+/// Delete everything that has been recorded about you
+#[poise::command(slash_command, prefix_command, ephemeral)]
+async fn forgetme(ctx: Context<'_>) -> Result<(), Error> {
+    let removed = ctx.data().store.forget_user(ctx.author().id.get()).await?;
+    let total: u64 = removed.iter().map(|(_, rows)| rows).sum();
+    let breakdown = removed
+        .iter()
+        .filter(|(_, rows)| *rows > 0)
+        .map(|(table, rows)| format!("{table}: {rows}"))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    if total == 0 {
+        ctx.say("There was nothing recorded about you.").await?;
+    } else {
+        ctx.say(format!("Removed {total} rows ({breakdown}). Run /optout to stop being recorded from now on.")).await?;
+    }
     Ok(())
 }
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    let (tx, rx) = database::new_write_queue(100);
 
-    tokio::spawn(database::writer_task(rx));
+    if env::args().any(|arg| arg == "--migrations-dry-run") {
+        if let Err(e) = database::Database::print_pending_migrations(&store::database_path()).await {
+            println!("Could not check migrations: {e}");
+        }
+        return;
+    }
+
+    if env::args().any(|arg| arg == "--vacuum") {
+        if let Err(e) = database::Database::switch_to_incremental_vacuum(&store::database_path()).await {
+            println!("Could not vacuum the database: {e}");
+        }
+        return;
+    }
 
+    let store = match store::open_from_env().await {
+        Ok(store) => store,
+        Err(e) => {
+            println!("Refusing to start: {e}");
+            return;
+        }
+    };
+
+    if env::args().any(|arg| arg == "--retention-dry-run") {
+        retention::print_report(store.as_ref(), unix_now()).await;
+        return;
+    }
+
+    // SCAN_GUILD may list several comma separated guild ids that are enabled on startup
+    if let Ok(scan_guilds) = env::var("SCAN_GUILD") {
+        for guild_id in scan_guilds.split(',').filter_map(|id| id.trim().parse::<u64>().ok()) {
+            store.set_guild_enabled(guild_id, true).await;
+        }
+    }
+
+    // Has to happen before the writer closes stale sessions, which end at the last coverage period
+    store.close_abandoned_coverage().await;
+
+    let run_id = match store.start_run(unix_now()).await {
+        Ok(run_id) => Some(run_id),
+        Err(e) => {
+            error!("Failed to record the run {}", e);
+            None
+        }
+    };
+
+    let (tx, rx) = store::new_write_queue();
+
+    let spool = Arc::new(spool::Spool::from_env());
+    let stop_writer = Arc::new(Notify::new());
+    let writer = tokio::spawn(store::writer_task(Arc::clone(&store), rx, Arc::clone(&spool), Arc::clone(&stop_writer)));
+    tokio::spawn(store::replay_task(Arc::clone(&store), Arc::clone(&spool)));
+    tokio::spawn(retention::retention_task(Arc::clone(&store)));
+
+    let scope_cache = Arc::new(scope::ScopeCache::default());
     let handler = Handler {
-        tx: tx.clone(),
+        tx,
+        store: Arc::clone(&store),
+        spool: Arc::clone(&spool),
+        scope: Arc::clone(&scope_cache),
+        changes: changes::ChangeDetector::from_env(),
+        identities: changes::IdentityTracker::default()
     };
 
     let token = env::var("BOT_TOKEN").expect("Expected a token in the environment");
@@ -108,23 +955,39 @@ async fn main() {
 
     let key_copy = Arc::clone(&key);
 
-    let intents: GatewayIntents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT | GatewayIntents::GUILDS | GatewayIntents::GUILD_MEMBERS | GatewayIntents::GUILD_PRESENCES;
+    let mut intents: GatewayIntents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::GUILDS | GatewayIntents::GUILD_MEMBERS | GatewayIntents::GUILD_PRESENCES | GatewayIntents::GUILD_VOICE_STATES;
+    if log_message_length() {
+        intents |= GatewayIntents::MESSAGE_CONTENT;
+    }
+
 
 
+    let (webserver, stop_webserver) = webserver::start(key_copy.to_string(), Arc::clone(&store));
 
-    thread::spawn(move || webserver::main(key_copy.to_string()));
+    let heartbeat_store = Arc::clone(&store);
+    tokio::spawn(async move {
+        let mut heartbeat = tokio::time::interval(Duration::from_secs(COVERAGE_HEARTBEAT_SECONDS));
+        loop {
+            heartbeat.tick().await;
+            heartbeat_store.touch_coverage(unix_now()).await;
+        }
+    });
 
 
+    let command_store = Arc::clone(&store);
+    let command_scope = Arc::clone(&scope_cache);
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![ping(), login()],
+            commands: vec![ping(), login(), tracking(), scope(), retention(), optout(), optin(), forgetme()],
             ..Default::default()
         })
         .setup(|ctx, _ready, framework| {
             Box::pin(async move {
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
                 Ok(Data {
-                    key: Arc::clone(&key).to_string()
+                    key: Arc::clone(&key).to_string(),
+                    store: command_store,
+                    scope: command_scope
                 })
             })
         })
@@ -132,9 +995,128 @@ async fn main() {
 
     
 
-    let client = ClientBuilder::new(token,intents)
+    let mut client = ClientBuilder::new(token,intents)
         .event_handler(handler)
         .framework(framework)
-        .await;
-    client.unwrap().start().await.unwrap();
-}
\ No newline at end of file
+        .await
+        .expect("Could not create the client");
+
+    // Shards that never connected are not stopped by shutdown_all, so the signal also cancels start itself
+    let shard_manager = Arc::clone(&client.shard_manager);
+    let signalled = tokio::select! {
+        result = client.start() => {
+            if let Err(e) = result {
+                error!("Client stopped {}", e);
+            }
+            false
+        },
+        _ = shutdown_signal() => {
+            println!("Shutting down");
+            shard_manager.shutdown_all().await;
+            true
+        }
+    };
+
+    // Events still arriving while the shards wind down are spooled instead of queued
+    stop_writer.notify_one();
+    drop(client);
+    store.close_coverage(None, if signalled { "shutdown" } else { "stopped" }, unix_now()).await;
+
+    let flushed = match tokio::time::timeout(Duration::from_secs(WRITER_SHUTDOWN_SECONDS), writer).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            error!("Writer stopped without flushing the queue {}", e);
+            false
+        },
+        Err(_) => {
+            println!("Writer did not finish within {} seconds", WRITER_SHUTDOWN_SECONDS);
+            false
+        }
+    };
+
+    // Jobs spooled while shutting down have to be on disk before the process exits
+    if tokio::task::spawn_blocking(move || spool.flush()).await.is_err() {
+        error!("Could not flush the spool");
+    }
+
+    // Joining blocks until rouille has stopped, which must not hold up a runtime worker
+    let _ = stop_webserver.send(());
+    match tokio::task::spawn_blocking(move || webserver.join()).await {
+        Ok(Ok(())) => {},
+        _ => error!("Webserver thread panicked")
+    }
+
+    // Only a run that lost nothing counts as a clean shutdown
+    if flushed && let Some(run_id) = run_id {
+        store.finish_run(run_id, unix_now()).await;
+    }
+    println!("Shutdown complete");
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where there is one.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).expect("Could not listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Could not listen for Ctrl-C {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn voice_state(channel_id: Option<u64>, self_mute: bool, self_stream: Option<bool>) -> VoiceState {
+        serde_json::from_value(json!({
+            "channel_id": channel_id.map(|id| id.to_string()),
+            "deaf": false,
+            "mute": false,
+            "self_deaf": false,
+            "self_mute": self_mute,
+            "self_stream": self_stream,
+            "self_video": false,
+            "session_id": "session",
+            "suppress": false,
+            "user_id": "1"
+        })).unwrap()
+    }
+
+    #[test]
+    fn channel_changes() {
+        assert_eq!(voice_changes(None, &voice_state(Some(10), false, None)), vec!("join"));
+        assert_eq!(voice_changes(Some(&voice_state(None, false, None)), &voice_state(Some(10), false, None)), vec!("join"));
+        assert_eq!(voice_changes(Some(&voice_state(Some(10), false, None)), &voice_state(None, false, None)), vec!("leave"));
+        assert_eq!(voice_changes(Some(&voice_state(Some(10), false, None)), &voice_state(Some(20), false, None)), vec!("move"));
+    }
+
+    #[test]
+    fn toggles_inside_a_channel() {
+        let old = voice_state(Some(10), false, None);
+        assert_eq!(voice_changes(Some(&old), &voice_state(Some(10), true, None)), vec!("self_mute"));
+        assert_eq!(voice_changes(Some(&old), &voice_state(Some(10), false, Some(true))), vec!("stream_start"));
+        assert_eq!(voice_changes(Some(&voice_state(Some(10), true, None)), &old), vec!("self_unmute"));
+        assert_eq!(voice_changes(Some(&old), &old), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn toggles_are_not_reported_when_joining_or_leaving() {
+        let muted = voice_state(Some(10), true, None);
+        assert_eq!(voice_changes(Some(&voice_state(None, false, None)), &muted), vec!("join"));
+        assert_eq!(voice_changes(Some(&muted), &voice_state(None, false, None)), vec!("leave"));
+    }
+
+    #[test]
+    fn moving_while_muting_reports_both() {
+        let old = voice_state(Some(10), false, None);
+        assert_eq!(voice_changes(Some(&old), &voice_state(Some(20), true, None)), vec!("move", "self_mute"));
+    }
+}
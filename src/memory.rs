@@ -0,0 +1,740 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use poise::serenity_prelude as serenity;
+use crate::database::{
+    self, ActivityRecord, CoveragePeriod, CustomStatus, CustomStatusChange, DataFilter, GuildConfig, IdentityJob, MemberEvent,
+    MessageEvent, PresenceJob, PresenceRow, Session, SpotifyPlay, UserIdentity, VoiceEvent, WriteJob
+};
+use crate::retention::Cutoffs;
+use crate::scope::TrackingRules;
+use crate::spool::SpooledJob;
+use crate::store::{Store, StoreError};
+
+/// Keeps everything in plain collections instead of a file, so the handler, the writer and the webserver can run
+/// without touching disk. Behaves like `Database` as far as the pages can tell, and loses everything on exit.
+#[derive(Default)]
+pub struct MemoryStore {
+    tables: Mutex<Tables>
+}
+
+#[derive(Default)]
+struct Tables {
+    guilds: HashMap<u64, GuildRow>,
+    tracking_rules: Vec<(u64, String, u64)>,
+    coverage: Vec<CoveragePeriod>,
+    runs: Vec<RunRow>,
+    opted_out: HashSet<u64>,
+    /// Username and the time it was seen
+    users: HashMap<u64, (String, u64)>,
+    identities: Vec<IdentityRow>,
+    presences: Vec<(PresenceRow, Vec<ActivityRecord>)>,
+    next_presence_id: u64,
+    job_ids: HashSet<String>,
+    spotify_plays: Vec<SpotifyPlay>,
+    sessions: Vec<(u64, Session)>,
+    voice_events: Vec<VoiceEvent>,
+    message_events: Vec<MessageEvent>,
+    member_events: Vec<MemberEvent>,
+    custom_statuses: Vec<(u64, CustomStatusChange)>
+}
+
+#[derive(Default)]
+struct GuildRow {
+    name: Option<String>,
+    enabled: bool,
+    track_bots: bool,
+    retention_days: Option<u64>
+}
+
+struct RunRow {
+    started_at: u64,
+    ended_at: Option<u64>,
+    clean_shutdown: bool
+}
+
+struct IdentityRow {
+    identity: UserIdentity,
+    first_seen: u64,
+    last_seen: u64
+}
+
+impl IdentityRow {
+    fn display_name(&self) -> String {
+        let identity = &self.identity;
+        identity.nickname.clone().or(identity.global_name.clone()).unwrap_or(identity.username.clone())
+    }
+}
+
+impl MemoryStore {
+    fn tables(&self) -> std::sync::MutexGuard<'_, Tables> {
+        self.tables.lock().unwrap()
+    }
+}
+
+impl Tables {
+    fn close_coverage(&mut self, shard_id: Option<u32>, reason: &str, time: u64) {
+        for period in self.coverage.iter_mut().filter(|p| p.ended_at.is_none() && shard_id.is_none_or(|id| p.shard_id == id)) {
+            period.ended_at = Some(time);
+            period.last_seen = time;
+            period.end_reason = Some(String::from(reason));
+        }
+    }
+
+    /// The guild of every expired row in a raw event table.
+    fn expired(&self, table: &str, cutoffs: &Cutoffs) -> Vec<Option<u64>> {
+        let rows: Vec<(Option<u64>, u64)> = match table {
+            "tracking_data" => self.presences.iter().map(|(row, _)| (row.guild_id, row.time)).collect(),
+            "voice_events" => self.voice_events.iter().map(|event| (Some(event.guild_id), event.time)).collect(),
+            "message_events" => self.message_events.iter().map(|event| (Some(event.guild_id), event.time)).collect(),
+            "member_events" => self.member_events.iter().map(|event| (Some(event.guild_id), event.time)).collect(),
+            _ => vec!()
+        };
+        rows
+            .into_iter()
+            .filter(|(guild_id, time)| cutoffs.for_guild(*guild_id).is_some_and(|cutoff| *time < cutoff))
+            .map(|(guild_id, _)| guild_id)
+            .collect()
+    }
+
+    fn usernames(&self, ids: &[u64]) -> HashMap<u64, String> {
+        let mut results: HashMap<u64, String> = HashMap::new();
+        for id in ids.iter() {
+            match self.users.get(id) {
+                Some((username, _)) => {
+                    results.insert(*id, username.clone());
+                },
+                None => println!("Could not find value for {id}")
+            }
+        }
+        results
+    }
+
+    /// Mirrors the SQL writers: a job with a `job_id` that was already written changes nothing.
+    fn write_job(&mut self, job: WriteJob, job_id: Option<&str>) {
+        if let Some(job_id) = job_id
+            && !self.job_ids.insert(String::from(job_id)) {
+            if let WriteJob::Presence(_) = job {
+                println!("Presence job {:?} was already written", Some(job_id));
+            }
+            return;
+        }
+
+        match job {
+            WriteJob::Presence(job) => self.write_presence(*job),
+            WriteJob::Voice(event) => self.voice_events.push(event),
+            WriteJob::Message(event) => self.message_events.push(event),
+            WriteJob::Member(event) => self.member_events.push(event),
+            WriteJob::Identity(job) => self.write_identity(job)
+        }
+    }
+
+    fn write_identity(&mut self, job: IdentityJob) {
+        let IdentityJob { identity, time } = job;
+        println!("Associating user {} with username {}", identity.user_id, identity.username);
+        if self.users.get(&identity.user_id).is_none_or(|(_, seen)| *seen <= time) {
+            self.users.insert(identity.user_id, (identity.username.clone(), time));
+        }
+
+        let latest = self.identities
+            .iter_mut()
+            .filter(|row| row.identity.guild_id == identity.guild_id && row.identity.user_id == identity.user_id)
+            .max_by_key(|row| row.last_seen);
+        match latest {
+            Some(latest) if latest.identity == identity => latest.last_seen = latest.last_seen.max(time),
+            _ => self.identities.push(IdentityRow { identity, first_seen: time, last_seen: time })
+        }
+    }
+
+    /// Like `database::write_presence`, a presence older than the user's latest one only adds its raw row.
+    fn write_presence(&mut self, job: PresenceJob) {
+        let is_latest = self.presences
+            .iter()
+            .filter(|(row, _)| row.user_id == job.user_id && row.guild_id == Some(job.guild_id))
+            .all(|(row, _)| job.time >= row.time);
+
+        self.next_presence_id += 1;
+        let row = PresenceRow {
+            id: self.next_presence_id,
+            guild_id: Some(job.guild_id),
+            user_id: job.user_id,
+            time: job.time,
+            status: job.status.clone(),
+            activity: job.activity.clone(),
+            activity_description: job.activity_description.clone(),
+            desktop_status: job.desktop_status.clone(),
+            mobile_status: job.mobile_status.clone(),
+            web_status: job.web_status.clone(),
+            source: Some(job.source.clone())
+        };
+
+        self.presences.push((row, job.activities.clone()));
+        if !is_latest {
+            return;
+        }
+        self.update_sessions(&job);
+
+        if let Some(play) = job.spotify {
+            self.record_spotify_play(play);
+        }
+
+        // Offline presences carry no activities, which says nothing about whether the custom status was cleared
+        if job.status != "offline" {
+            self.record_custom_status(job.guild_id, job.user_id, job.time, job.custom_status);
+        }
+    }
+
+    fn update_sessions(&mut self, job: &PresenceJob) {
+        let mut values: Vec<(String, String)> = vec!((String::from("status"), job.status.clone()));
+        for activity in job.activities.iter() {
+            let value = (String::from("activity"), activity.name.clone());
+            if !values.contains(&value) {
+                values.push(value);
+            }
+        }
+
+        let mut still_open: Vec<(String, String)> = vec!();
+        let open = self.sessions
+            .iter_mut()
+            .filter(|(guild_id, session)| *guild_id == job.guild_id && session.user_id == job.user_id && session.ended_at.is_none());
+        for (_, session) in open {
+            if values.iter().any(|(k, v)| *k == session.kind && *v == session.value) {
+                still_open.push((session.kind.clone(), session.value.clone()));
+            } else {
+                session.ended_at = Some(job.time);
+                session.end_reason = Some(String::from("changed"));
+            }
+        }
+
+        for (kind, value) in values {
+            if !still_open.iter().any(|(k, v)| *k == kind && *v == value) {
+                self.sessions.push((job.guild_id, Session {
+                    user_id: job.user_id,
+                    kind,
+                    value,
+                    started_at: job.time,
+                    ended_at: None,
+                    end_reason: None
+                }));
+            }
+        }
+    }
+
+    fn record_spotify_play(&mut self, play: SpotifyPlay) {
+        let latest = self.spotify_plays
+            .iter_mut()
+            .filter(|p| p.guild_id == play.guild_id && p.user_id == play.user_id)
+            .max_by_key(|p| p.started_at);
+
+        if let Some(latest) = latest
+            && latest.track_id == play.track_id && latest.ends_at.is_none_or(|ends_at| play.started_at <= ends_at) {
+            latest.ends_at = play.ends_at;
+            return;
+        }
+        self.spotify_plays.push(play);
+    }
+
+    fn record_custom_status(&mut self, guild_id: u64, user_id: u64, time: u64, status: Option<CustomStatus>) {
+        // The last of the latest rows wins, like ordering by time and then id
+        let latest = self.custom_statuses
+            .iter()
+            .filter(|(g, change)| *g == guild_id && change.user_id == user_id)
+            .max_by_key(|(_, change)| change.time)
+            .and_then(|(_, change)| change.status.clone());
+
+        if latest == status {
+            return;
+        }
+        self.custom_statuses.push((guild_id, CustomStatusChange { user_id, time, status }));
+    }
+}
+
+/// Same meaning as `guild_id = ...` in SQL: a filter that is not a number matches nothing.
+fn matches(filter: Option<&str>, value: u64) -> bool {
+    filter.is_none_or(|filter| filter.parse::<u64>().ok() == Some(value))
+}
+
+/// `LIKE '%search%'`, which ignores ASCII case.
+fn like(value: &str, search: &str) -> bool {
+    value.to_ascii_lowercase().contains(&search.to_ascii_lowercase())
+}
+
+fn matches_platform(row: &PresenceRow, platform: &str) -> bool {
+    let online = |status: &Option<String>| status.as_deref().is_some_and(|s| s != "offline");
+    let (desktop, mobile, web) = (&row.desktop_status, &row.mobile_status, &row.web_status);
+    match platform {
+        "desktop" => online(desktop),
+        "mobile" => online(mobile),
+        "web" => online(web),
+        "desktop_only" => desktop.is_some() && mobile.is_none() && web.is_none(),
+        "mobile_only" => mobile.is_some() && desktop.is_none() && web.is_none(),
+        "web_only" => web.is_some() && desktop.is_none() && mobile.is_none(),
+        _ => true
+    }
+}
+
+fn matches_filter(row: &PresenceRow, activities: &[ActivityRecord], filter: &DataFilter<'_>) -> bool {
+    let any_activity = |check: &dyn Fn(&ActivityRecord) -> bool| activities.iter().any(check);
+
+    row.guild_id.map_or(filter.guild_id.is_none(), |guild_id| matches(filter.guild_id, guild_id))
+        && matches(filter.user_id, row.user_id)
+        && filter.status.is_none_or(|status| row.status == status)
+        && filter.activity.is_none_or(|activity| row.activity == activity)
+        && filter.activity_description.is_none_or(|description| row.activity_description == description)
+        && filter.activity_type.is_none_or(|kind| any_activity(&|a| a.kind == kind))
+        && filter.activity_state.is_none_or(|state| any_activity(&|a| a.state.as_deref() == Some(state)))
+        && filter.application_id.is_none_or(|id| any_activity(&|a| a.application_id.is_some_and(|a_id| matches(Some(id), a_id))))
+        && filter.custom_status.is_none_or(|text| any_activity(&|a| a.kind == "Custom" && a.state.as_deref().is_some_and(|state| like(state, text))))
+        && filter.platform.is_none_or(|platform| matches_platform(row, platform))
+        && filter.time_lt.is_none_or(|time| row.time < *time)
+        && filter.time_mt.is_none_or(|time| row.time > *time)
+}
+
+/// Counts per key, most first, keeping the first value seen for each key.
+fn count_by<T, K: PartialEq, V>(items: impl Iterator<Item = T>, key: impl Fn(&T) -> K, value: impl Fn(&T) -> V, limit: u64) -> Vec<(V, u64)> {
+    let mut counts: Vec<(K, V, u64)> = vec!();
+    for item in items {
+        let k = key(&item);
+        match counts.iter_mut().find(|(existing, _, _)| *existing == k) {
+            Some((_, _, count)) => *count += 1,
+            None => counts.push((k, value(&item), 1))
+        }
+    }
+    counts.sort_by_key(|(_, _, count)| Reverse(*count));
+    counts.into_iter().take(limit as usize).map(|(_, v, count)| (v, count)).collect()
+}
+
+#[serenity::async_trait]
+impl Store for MemoryStore {
+    async fn add_tracking_rule(&self, guild_id: u64, kind: &str, target_id: u64) {
+        let mut tables = self.tables();
+        let rule = (guild_id, String::from(kind), target_id);
+        if !tables.tracking_rules.contains(&rule) {
+            tables.tracking_rules.push(rule);
+        }
+    }
+
+    async fn remove_tracking_rule(&self, guild_id: u64, kind: &str, target_id: u64) -> bool {
+        let mut tables = self.tables();
+        let before = tables.tracking_rules.len();
+        tables.tracking_rules.retain(|(g, k, t)| !(*g == guild_id && k == kind && *t == target_id));
+        tables.tracking_rules.len() < before
+    }
+
+    async fn set_track_bots(&self, guild_id: u64, track_bots: bool) {
+        self.tables().guilds.entry(guild_id).or_default().track_bots = track_bots;
+    }
+
+    async fn get_tracking_rules(&self, guild_id: u64) -> Result<TrackingRules, StoreError> {
+        let tables = self.tables();
+        let mut rules = TrackingRules {
+            track_bots: tables.guilds.get(&guild_id).is_some_and(|guild| guild.track_bots),
+            ..Default::default()
+        };
+
+        for (_, kind, target_id) in tables.tracking_rules.iter().filter(|(g, _, _)| *g == guild_id) {
+            match kind.as_str() {
+                "include_role" => rules.include_roles.push(*target_id),
+                "exclude_role" => rules.exclude_roles.push(*target_id),
+                "allow_user" => rules.allow_users.push(*target_id),
+                "deny_user" => rules.deny_users.push(*target_id),
+                _ => println!("Ignoring unknown tracking rule {kind}")
+            }
+        }
+
+        Ok(rules)
+    }
+
+    async fn register_guild(&self, guild_id: u64, name: &str) {
+        self.tables().guilds.entry(guild_id).or_default().name = Some(String::from(name));
+    }
+
+    async fn set_guild_enabled(&self, guild_id: u64, enabled: bool) {
+        self.tables().guilds.entry(guild_id).or_default().enabled = enabled;
+    }
+
+    async fn is_guild_enabled(&self, guild_id: u64) -> Result<bool, StoreError> {
+        Ok(self.tables().guilds.get(&guild_id).is_some_and(|guild| guild.enabled))
+    }
+
+    async fn get_guilds(&self) -> Vec<GuildConfig> {
+        let tables = self.tables();
+        let mut guilds: Vec<(&Option<String>, GuildConfig)> = tables.guilds
+            .iter()
+            .map(|(guild_id, guild)| (&guild.name, GuildConfig {
+                guild_id: *guild_id,
+                name: guild.name.clone().unwrap_or(String::from("Unknown guild")),
+                enabled: guild.enabled,
+                retention_days: guild.retention_days
+            }))
+            .collect();
+        guilds.sort_by(|a, b| a.0.cmp(b.0));
+        guilds.into_iter().map(|(_, guild)| guild).collect()
+    }
+
+    async fn set_retention_days(&self, guild_id: u64, days: Option<u64>) {
+        self.tables().guilds.entry(guild_id).or_default().retention_days = days;
+    }
+
+    async fn count_expired(&self, table: &str, cutoffs: &Cutoffs) -> Vec<(Option<u64>, u64)> {
+        let mut counts: Vec<(Option<u64>, u64)> = vec!();
+        for guild_id in self.tables().expired(table, cutoffs) {
+            match counts.iter_mut().find(|(id, _)| *id == guild_id) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((guild_id, 1))
+            }
+        }
+        counts
+    }
+
+    async fn delete_expired(&self, table: &str, cutoffs: &Cutoffs, limit: u64) -> Result<u64, StoreError> {
+        let mut tables = self.tables();
+
+        fn remove<T>(rows: &mut Vec<T>, limit: u64, expired: impl Fn(&T) -> bool) -> u64 {
+            let mut removed = 0;
+            rows.retain(|row| {
+                if removed < limit && expired(row) {
+                    removed += 1;
+                    return false;
+                }
+                true
+            });
+            removed
+        }
+        let expired = |guild_id: Option<u64>, time: u64| cutoffs.for_guild(guild_id).is_some_and(|cutoff| time < cutoff);
+
+        // Activities live inside their presence, so they go with it
+        Ok(match table {
+            "tracking_data" => remove(&mut tables.presences, limit, |(row, _)| expired(row.guild_id, row.time)),
+            "voice_events" => remove(&mut tables.voice_events, limit, |event| expired(Some(event.guild_id), event.time)),
+            "message_events" => remove(&mut tables.message_events, limit, |event| expired(Some(event.guild_id), event.time)),
+            "member_events" => remove(&mut tables.member_events, limit, |event| expired(Some(event.guild_id), event.time)),
+            _ => return Err(format!("no such table {table}").into())
+        })
+    }
+
+    /// Nothing to give back, removed rows are freed right away.
+    async fn incremental_vacuum(&self) {}
+
+    async fn open_coverage(&self, shard_id: u32, reason: &str, time: u64) {
+        let mut tables = self.tables();
+        tables.close_coverage(Some(shard_id), "reconnected", time);
+        tables.coverage.push(CoveragePeriod {
+            shard_id,
+            started_at: time,
+            last_seen: time,
+            ended_at: None,
+            start_reason: String::from(reason),
+            end_reason: None
+        });
+    }
+
+    async fn close_coverage(&self, shard_id: Option<u32>, reason: &str, time: u64) {
+        self.tables().close_coverage(shard_id, reason, time);
+    }
+
+    async fn touch_coverage(&self, time: u64) {
+        for period in self.tables().coverage.iter_mut().filter(|p| p.ended_at.is_none()) {
+            period.last_seen = time;
+        }
+    }
+
+    async fn close_abandoned_coverage(&self) {
+        let mut closed = 0;
+        for period in self.tables().coverage.iter_mut().filter(|p| p.ended_at.is_none()) {
+            period.ended_at = Some(period.last_seen);
+            period.end_reason = Some(String::from("crash"));
+            closed += 1;
+        }
+        if closed > 0 {
+            println!("Closed {closed} coverage periods left open by the previous run");
+        }
+    }
+
+    async fn get_coverage(&self, limit: u64) -> Vec<CoveragePeriod> {
+        let mut periods = self.tables().coverage.clone();
+        periods.sort_by_key(|period| Reverse(period.started_at));
+        periods.truncate(limit as usize);
+        periods
+    }
+
+    async fn get_coverage_gaps(&self, from: u64, to: u64, now: u64) -> Vec<(u64, u64)> {
+        let mut periods: Vec<(u64, u64)> = self.tables().coverage
+            .iter()
+            .filter(|p| p.started_at <= to)
+            .map(|p| (p.started_at, p.ended_at.unwrap_or(now)))
+            .collect();
+        periods.sort_by_key(|(started_at, _)| *started_at);
+        database::coverage_gaps(periods, from, to, now)
+    }
+
+    async fn start_run(&self, time: u64) -> Result<u64, StoreError> {
+        let mut tables = self.tables();
+        let last_seen = tables.coverage.iter().map(|p| p.last_seen).max();
+        let mut closed = 0;
+        for run in tables.runs.iter_mut().filter(|run| run.ended_at.is_none()) {
+            run.ended_at = Some(last_seen.unwrap_or(run.started_at));
+            closed += 1;
+        }
+        if closed > 0 {
+            println!("The previous run did not shut down cleanly");
+        }
+
+        tables.runs.push(RunRow { started_at: time, ended_at: None, clean_shutdown: false });
+        Ok(tables.runs.len() as u64)
+    }
+
+    async fn finish_run(&self, run_id: u64, time: u64) {
+        // Run ids count from 1 like the AUTOINCREMENT ids of `runs`
+        if let Some(run) = self.tables().runs.get_mut((run_id as usize).saturating_sub(1)) {
+            run.ended_at = Some(time);
+            run.clean_shutdown = true;
+        }
+    }
+
+    async fn set_opted_out(&self, user_id: u64, opted_out: bool, _time: u64) {
+        let mut tables = self.tables();
+        if opted_out {
+            tables.opted_out.insert(user_id);
+        } else {
+            tables.opted_out.remove(&user_id);
+        }
+    }
+
+    async fn get_opted_out(&self) -> Result<HashSet<u64>, StoreError> {
+        Ok(self.tables().opted_out.clone())
+    }
+
+    async fn forget_user(&self, user_id: u64) -> Result<Vec<(&'static str, u64)>, StoreError> {
+        let mut tables = self.tables();
+
+        fn remove<T>(rows: &mut Vec<T>, keep: impl Fn(&T) -> bool) -> u64 {
+            let before = rows.len();
+            rows.retain(keep);
+            (before - rows.len()) as u64
+        }
+
+        let activities = tables.presences
+            .iter()
+            .filter(|(row, _)| row.user_id == user_id)
+            .map(|(_, activities)| activities.len() as u64)
+            .sum();
+
+        Ok(vec!(
+            ("presence_activities", activities),
+            ("tracking_data", remove(&mut tables.presences, |(row, _)| row.user_id != user_id)),
+            ("users", tables.users.remove(&user_id).map_or(0, |_| 1)),
+            ("spotify_plays", remove(&mut tables.spotify_plays, |play| play.user_id != user_id)),
+            ("sessions", remove(&mut tables.sessions, |(_, session)| session.user_id != user_id)),
+            ("voice_events", remove(&mut tables.voice_events, |event| event.user_id != user_id)),
+            ("message_events", remove(&mut tables.message_events, |event| event.user_id != user_id)),
+            ("member_events", remove(&mut tables.member_events, |event| event.user_id != user_id)),
+            ("user_identities", remove(&mut tables.identities, |row| row.identity.user_id != user_id)),
+            ("custom_statuses", remove(&mut tables.custom_statuses, |(_, change)| change.user_id != user_id))
+        ))
+    }
+
+    async fn get_names_at(&self, lookups: Vec<(u64, u64, u64)>) -> HashMap<(u64, u64, u64), String> {
+        let tables = self.tables();
+        let mut results: HashMap<(u64, u64, u64), String> = HashMap::new();
+        let mut missing: Vec<u64> = vec!();
+
+        for (guild_id, user_id, time) in lookups.iter() {
+            let history = || tables.identities.iter().filter(|row| row.identity.guild_id == *guild_id && row.identity.user_id == *user_id);
+            let row = history()
+                .filter(|row| row.first_seen <= *time)
+                .max_by_key(|row| row.first_seen)
+                .or_else(|| history().min_by_key(|row| row.first_seen));
+
+            match row {
+                Some(row) => {
+                    results.insert((*guild_id, *user_id, *time), row.display_name());
+                },
+                None => missing.push(*user_id)
+            }
+        }
+
+        if !missing.is_empty() {
+            let current = tables.usernames(&missing);
+            for lookup in lookups.iter() {
+                if let Some(name) = current.get(&lookup.1) {
+                    results.entry(*lookup).or_insert(name.clone());
+                }
+            }
+        }
+
+        results
+    }
+
+    async fn get_username(&self, user_id: u64) -> Result<Option<String>, StoreError> {
+        Ok(self.tables().users.get(&user_id).map(|(username, _)| username.clone()))
+    }
+
+    async fn get_usernames(&self, ids: Vec<u64>) -> HashMap<u64, String> {
+        self.tables().usernames(&ids)
+    }
+
+    async fn get_activities(&self, tracking_ids: Vec<u64>) -> HashMap<u64, Vec<ActivityRecord>> {
+        self.tables().presences
+            .iter()
+            .filter(|(row, activities)| tracking_ids.contains(&row.id) && !activities.is_empty())
+            .map(|(row, activities)| (row.id, activities.clone()))
+            .collect()
+    }
+
+    /// Nothing in memory can fail to be written.
+    async fn write_batch(&self, jobs: Vec<WriteJob>) -> Vec<WriteJob> {
+        let size = jobs.len();
+        let mut tables = self.tables();
+        for job in jobs {
+            tables.write_job(job, None);
+        }
+        println!("Wrote batch of {} jobs", size);
+        vec!()
+    }
+
+    async fn replay(&self, jobs: Vec<SpooledJob>) -> Result<Vec<SpooledJob>, StoreError> {
+        let mut tables = self.tables();
+        for spooled in jobs {
+            tables.write_job(spooled.job, Some(&spooled.job_id));
+        }
+        Ok(vec!())
+    }
+
+    async fn close_stale_sessions(&self) {
+        let mut tables = self.tables();
+        let end = tables.coverage.iter().filter_map(|p| p.ended_at).max()
+            .or(tables.presences.iter().map(|(row, _)| row.time).max());
+
+        let mut closed = 0;
+        for (_, session) in tables.sessions.iter_mut().filter(|(_, session)| session.ended_at.is_none()) {
+            session.ended_at = Some(end.unwrap_or(session.started_at).max(session.started_at));
+            session.end_reason = Some(String::from("restart"));
+            closed += 1;
+        }
+        if closed > 0 {
+            println!("Closed {closed} sessions left open by the previous run");
+        }
+    }
+
+    async fn get_data(&self, page: &u64, filter: &DataFilter<'_>) -> Vec<PresenceRow> {
+        self.tables().presences
+            .iter()
+            .filter(|(row, activities)| matches_filter(row, activities, filter))
+            .skip(database::page_offset(*page) as usize)
+            .take(database::PAGE_SIZE as usize)
+            .map(|(row, _)| row.clone())
+            .collect()
+    }
+
+    async fn explain_data_query(&self, _page: &u64, _filter: &DataFilter<'_>) -> Result<Vec<(i64, i64, String)>, StoreError> {
+        Err("the in-memory store has no query plan".into())
+    }
+
+    async fn get_recent_plays(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<SpotifyPlay> {
+        let mut plays: Vec<SpotifyPlay> = self.tables().spotify_plays
+            .iter()
+            .filter(|play| matches(guild_id, play.guild_id) && matches(user_id, play.user_id))
+            .cloned()
+            .collect();
+        plays.sort_by_key(|play| Reverse(play.started_at));
+        plays.truncate(limit as usize);
+        plays
+    }
+
+    async fn get_top_artists(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<(String, u64)> {
+        let tables = self.tables();
+        let plays = tables.spotify_plays.iter().filter(|play| matches(guild_id, play.guild_id) && matches(user_id, play.user_id));
+        count_by(plays, |play| play.artist.clone(), |play| play.artist.clone(), limit)
+    }
+
+    async fn get_top_tracks(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<(String, String, u64)> {
+        let tables = self.tables();
+        let plays = tables.spotify_plays.iter().filter(|play| matches(guild_id, play.guild_id) && matches(user_id, play.user_id));
+        count_by(plays, |play| play.track_id.clone(), |play| (play.title.clone(), play.artist.clone()), limit)
+            .into_iter()
+            .map(|((title, artist), plays)| (title, artist, plays))
+            .collect()
+    }
+
+    async fn get_sessions(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<Session> {
+        let mut sessions: Vec<Session> = self.tables().sessions
+            .iter()
+            .filter(|(g, session)| matches(guild_id, *g) && matches(user_id, session.user_id))
+            .map(|(_, session)| session.clone())
+            .collect();
+        sessions.sort_by_key(|session| Reverse(session.started_at));
+        sessions.truncate(limit as usize);
+        sessions
+    }
+
+    async fn get_activity_totals(&self, guild_id: Option<&str>, user_id: Option<&str>, now: u64, limit: u64) -> Vec<(String, u64)> {
+        let sessions = self.tables().sessions
+            .iter()
+            .filter(|(g, session)| matches(guild_id, *g) && matches(user_id, session.user_id) && session.kind == "activity")
+            .map(|(_, session)| (session.user_id, session.value.clone(), session.started_at, session.ended_at.unwrap_or(now)))
+            .collect();
+        database::activity_totals(sessions, limit)
+    }
+
+    async fn get_voice_events(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<VoiceEvent> {
+        let mut events: Vec<VoiceEvent> = self.tables().voice_events
+            .iter()
+            .filter(|event| matches(guild_id, event.guild_id) && matches(user_id, event.user_id))
+            .cloned()
+            .collect();
+        events.sort_by_key(|event| Reverse(event.time));
+        events.truncate(limit as usize);
+        events
+    }
+
+    async fn get_message_counts_by_user(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<(u64, u64)> {
+        let tables = self.tables();
+        let messages = tables.message_events.iter().filter(|event| matches(guild_id, event.guild_id) && matches(user_id, event.user_id));
+        count_by(messages, |event| event.user_id, |event| event.user_id, limit)
+    }
+
+    async fn get_message_counts_by_channel(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<(String, u64)> {
+        let tables = self.tables();
+        let messages: Vec<&MessageEvent> = tables.message_events
+            .iter()
+            .filter(|event| matches(guild_id, event.guild_id) && matches(user_id, event.user_id))
+            .collect();
+
+        // Like MAX(channel_name) in SQL, so every channel is shown under one name
+        let name = |channel_id: u64| -> String {
+            messages
+                .iter()
+                .filter(|event| event.channel_id == channel_id)
+                .filter_map(|event| event.channel_name.clone())
+                .max()
+                .unwrap_or(channel_id.to_string())
+        };
+        count_by(messages.iter(), |event| event.channel_id, |event| name(event.channel_id), limit)
+    }
+
+    async fn get_member_events(&self, guild_id: Option<&str>, user_ids: Vec<u64>, from: u64, to: u64) -> Vec<MemberEvent> {
+        let mut events: Vec<MemberEvent> = self.tables().member_events
+            .iter()
+            .filter(|event| matches(guild_id, event.guild_id) && user_ids.contains(&event.user_id) && event.time >= from && event.time <= to)
+            .cloned()
+            .collect();
+        events.sort_by_key(|event| event.time);
+        events
+    }
+
+    async fn get_custom_status_history(&self, guild_id: Option<&str>, user_id: Option<&str>, search: Option<&str>, limit: u64) -> Vec<CustomStatusChange> {
+        let text_matches = |change: &CustomStatusChange| search.is_none_or(|search| {
+            change.status.as_ref().and_then(|status| status.text.as_deref()).is_some_and(|text| like(text, search))
+        });
+
+        let mut history: Vec<CustomStatusChange> = self.tables().custom_statuses
+            .iter()
+            .filter(|(g, change)| matches(guild_id, *g) && matches(user_id, change.user_id) && text_matches(change))
+            .map(|(_, change)| change.clone())
+            .collect();
+        history.sort_by_key(|change| Reverse(change.time));
+        history.truncate(limit as usize);
+        history
+    }
+}
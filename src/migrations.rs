@@ -0,0 +1,440 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One change to the schema. Migrations run in order, each in its own transaction, and are recorded in `schema_version`.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub steps: &'static [Step]
+}
+
+pub enum Step {
+    Sql(&'static str),
+    /// Only added when missing, so the baseline also fits databases created before migrations existed.
+    AddColumn(&'static str, &'static str, &'static str)
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Sql(sql) => write!(f, "{}", sql.split_whitespace().collect::<Vec<&str>>().join(" ")),
+            Step::AddColumn(table, column, definition) => write!(f, "ALTER TABLE {table} ADD COLUMN {column} {definition} (if missing)")
+        }
+    }
+}
+
+/// Never edit a migration that has shipped, append a new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Baseline schema",
+        steps: &[
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS users (
+                id                      INTEGER PRIMARY KEY,
+                username                MEDIUMTEXT
+            )"),
+            // Columns are appended in the order they were introduced, the dashboard reads tracking_data by position
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS tracking_data (
+                id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id                 INTEGER,
+                time                    INTEGER,
+                status                  TINYTEXT,
+                activity                MEDIUMTEXT,
+                activity_description    MEDIUMTEXT
+            )"),
+            Step::AddColumn("tracking_data", "desktop_status", "TINYTEXT"),
+            Step::AddColumn("tracking_data", "mobile_status", "TINYTEXT"),
+            Step::AddColumn("tracking_data", "web_status", "TINYTEXT"),
+            Step::AddColumn("tracking_data", "guild_id", "INTEGER"),
+            Step::AddColumn("tracking_data", "source", "TINYTEXT"),
+            Step::AddColumn("tracking_data", "job_id", "TEXT"),
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS guilds (
+                guild_id                INTEGER PRIMARY KEY,
+                name                    MEDIUMTEXT,
+                enabled                 INTEGER DEFAULT 0
+            )"),
+            Step::AddColumn("guilds", "track_bots", "INTEGER DEFAULT 0"),
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS tracking_rules (
+                guild_id                INTEGER,
+                kind                    TINYTEXT,
+                target_id               INTEGER,
+                PRIMARY KEY (guild_id, kind, target_id)
+            )"),
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS coverage (
+                id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+                shard_id                INTEGER,
+                started_at              INTEGER,
+                last_seen               INTEGER,
+                ended_at                INTEGER,
+                start_reason            TINYTEXT,
+                end_reason              TINYTEXT
+            )"),
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS opted_out (
+                user_id                 INTEGER PRIMARY KEY,
+                time                    INTEGER
+            )"),
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS user_identities (
+                id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+                guild_id                INTEGER,
+                user_id                 INTEGER,
+                username                MEDIUMTEXT,
+                global_name             MEDIUMTEXT,
+                nickname                MEDIUMTEXT,
+                avatar                  TINYTEXT,
+                first_seen              INTEGER,
+                last_seen               INTEGER
+            )"),
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS presence_activities (
+                id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+                tracking_id             INTEGER,
+                position                INTEGER,
+                name                    MEDIUMTEXT,
+                details                 MEDIUMTEXT
+            )"),
+            Step::AddColumn("presence_activities", "kind", "TINYTEXT"),
+            Step::AddColumn("presence_activities", "state", "MEDIUMTEXT"),
+            Step::AddColumn("presence_activities", "started_at", "INTEGER"),
+            Step::AddColumn("presence_activities", "ends_at", "INTEGER"),
+            Step::AddColumn("presence_activities", "party_size", "INTEGER"),
+            Step::AddColumn("presence_activities", "party_max", "INTEGER"),
+            Step::AddColumn("presence_activities", "application_id", "INTEGER"),
+            Step::AddColumn("presence_activities", "large_text", "MEDIUMTEXT"),
+            Step::AddColumn("presence_activities", "small_text", "MEDIUMTEXT"),
+            Step::AddColumn("presence_activities", "url", "MEDIUMTEXT"),
+            Step::AddColumn("presence_activities", "emoji", "MEDIUMTEXT"),
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS spotify_plays (
+                id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id                 INTEGER,
+                track_id                TINYTEXT,
+                title                   MEDIUMTEXT,
+                artist                  MEDIUMTEXT,
+                album                   MEDIUMTEXT,
+                started_at              INTEGER,
+                ends_at                 INTEGER
+            )"),
+            Step::AddColumn("spotify_plays", "guild_id", "INTEGER"),
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS sessions (
+                id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+                guild_id                INTEGER,
+                user_id                 INTEGER,
+                kind                    TINYTEXT,
+                value                   MEDIUMTEXT,
+                started_at              INTEGER,
+                ended_at                INTEGER,
+                end_reason              TINYTEXT
+            )"),
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS voice_events (
+                id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+                guild_id                INTEGER,
+                user_id                 INTEGER,
+                time                    INTEGER,
+                kind                    TINYTEXT,
+                channel_id              INTEGER,
+                channel_name            MEDIUMTEXT,
+                previous_channel_id     INTEGER,
+                previous_channel_name   MEDIUMTEXT
+            )"),
+            Step::AddColumn("voice_events", "job_id", "TEXT"),
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS message_events (
+                id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+                guild_id                INTEGER,
+                user_id                 INTEGER,
+                channel_id              INTEGER,
+                channel_name            MEDIUMTEXT,
+                time                    INTEGER,
+                length                  INTEGER,
+                attachments             INTEGER,
+                is_reply                INTEGER,
+                in_thread               INTEGER
+            )"),
+            Step::AddColumn("message_events", "job_id", "TEXT"),
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS member_events (
+                id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+                guild_id                INTEGER,
+                user_id                 INTEGER,
+                time                    INTEGER,
+                kind                    TINYTEXT,
+                nick_before             MEDIUMTEXT,
+                nick_after              MEDIUMTEXT,
+                roles_added             MEDIUMTEXT,
+                roles_removed           MEDIUMTEXT
+            )"),
+            Step::AddColumn("member_events", "job_id", "TEXT"),
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS custom_statuses (
+                id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+                guild_id                INTEGER,
+                user_id                 INTEGER,
+                time                    INTEGER,
+                text                    MEDIUMTEXT,
+                emoji                   MEDIUMTEXT
+            )"),
+            // Spooled jobs carry an id so replaying one twice inserts nothing the second time
+            Step::Sql("CREATE UNIQUE INDEX IF NOT EXISTS tracking_data_job_id ON tracking_data (job_id)"),
+            Step::Sql("CREATE UNIQUE INDEX IF NOT EXISTS voice_events_job_id ON voice_events (job_id)"),
+            Step::Sql("CREATE UNIQUE INDEX IF NOT EXISTS message_events_job_id ON message_events (job_id)"),
+            Step::Sql("CREATE UNIQUE INDEX IF NOT EXISTS member_events_job_id ON member_events (job_id)")
+        ]
+    },
+    Migration {
+        version: 2,
+        description: "Indexes for the dashboard filters on tracking_data",
+        steps: &[
+            Step::Sql("CREATE INDEX IF NOT EXISTS tracking_data_user_time ON tracking_data (user_id, time)"),
+            Step::Sql("CREATE INDEX IF NOT EXISTS tracking_data_activity_time ON tracking_data (activity, time)"),
+            Step::Sql("CREATE INDEX IF NOT EXISTS tracking_data_status_time ON tracking_data (status, time)")
+        ]
+    },
+    Migration {
+        version: 3,
+        description: "Runs with a clean shutdown marker",
+        steps: &[
+            Step::Sql("
+            CREATE TABLE IF NOT EXISTS runs (
+                id                      INTEGER PRIMARY KEY AUTOINCREMENT,
+                started_at              INTEGER,
+                ended_at                INTEGER,
+                clean_shutdown          INTEGER DEFAULT 0
+            )")
+        ]
+    },
+    Migration {
+        version: 4,
+        description: "Per-guild retention of raw events",
+        steps: &[
+            // NULL uses RETENTION_DAYS, 0 keeps the guild's rows forever
+            Step::AddColumn("guilds", "retention_days", "INTEGER"),
+            Step::Sql("CREATE INDEX IF NOT EXISTS tracking_data_time ON tracking_data (time)"),
+            Step::Sql("CREATE INDEX IF NOT EXISTS voice_events_time ON voice_events (time)"),
+            Step::Sql("CREATE INDEX IF NOT EXISTS message_events_time ON message_events (time)"),
+            Step::Sql("CREATE INDEX IF NOT EXISTS member_events_time ON member_events (time)"),
+            Step::Sql("CREATE INDEX IF NOT EXISTS presence_activities_tracking_id ON presence_activities (tracking_id)")
+        ]
+    },
+    Migration {
+        version: 5,
+        description: "Unknown attachment counts",
+        steps: &[
+            // Messages recorded without MESSAGE_CONTENT have no length and a count of 0 that was never known
+            Step::Sql("UPDATE message_events SET attachments = NULL WHERE length IS NULL")
+        ]
+    },
+    Migration {
+        version: 6,
+        description: "Replayable identity jobs",
+        steps: &[
+            // When the username was seen, so a spooled identity never replaces a newer one
+            Step::AddColumn("users", "updated_at", "INTEGER"),
+            Step::AddColumn("user_identities", "job_id", "TEXT"),
+            Step::Sql("CREATE UNIQUE INDEX IF NOT EXISTS user_identities_job_id ON user_identities (job_id)")
+        ]
+    },
+    Migration {
+        version: 7,
+        description: "Indexes for the per-user lookups of every presence write",
+        steps: &[
+            Step::Sql("CREATE INDEX IF NOT EXISTS sessions_user_ended ON sessions (guild_id, user_id, ended_at)"),
+            Step::Sql("CREATE INDEX IF NOT EXISTS custom_statuses_user_time ON custom_statuses (guild_id, user_id, time)"),
+            Step::Sql("CREATE INDEX IF NOT EXISTS spotify_plays_user_started ON spotify_plays (guild_id, user_id, started_at)"),
+            Step::Sql("CREATE INDEX IF NOT EXISTS user_identities_user_seen ON user_identities (guild_id, user_id, last_seen)")
+        ]
+    }
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    /// The database was migrated by a newer build. Running an older one against it could corrupt data.
+    DatabaseNewer { database: u32, binary: u32 },
+    Sql(libsql::Error)
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::DatabaseNewer { database, binary } => write!(f, "database is at schema version {database} but this build only knows up to {binary}"),
+            MigrationError::Sql(e) => write!(f, "{e}")
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<libsql::Error> for MigrationError {
+    fn from(e: libsql::Error) -> Self {
+        MigrationError::Sql(e)
+    }
+}
+
+fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+/// The highest applied version, 0 for a database that never ran a migration. Does not write anything.
+async fn current_version(conn: &libsql::Connection) -> Result<u32, libsql::Error> {
+    let exists = conn
+        .query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'", ())
+        .await?
+        .next()
+        .await?
+        .is_some();
+    if !exists {
+        return Ok(0);
+    }
+
+    let mut rows = conn.query("SELECT MAX(version) FROM schema_version", ()).await?;
+    match rows.next().await? {
+        Some(row) => Ok(row.get::<Option<u32>>(0)?.unwrap_or(0)),
+        None => Ok(0)
+    }
+}
+
+async fn pending(conn: &libsql::Connection) -> Result<Vec<&'static Migration>, MigrationError> {
+    let current = current_version(conn).await?;
+    let latest = latest_version();
+    if current > latest {
+        return Err(MigrationError::DatabaseNewer { database: current, binary: latest });
+    }
+
+    Ok(MIGRATIONS.iter().filter(|migration| migration.version > current).collect())
+}
+
+/// Applies every pending migration. Refuses to touch a database that is newer than this build.
+pub async fn run(conn: &libsql::Connection) -> Result<(), MigrationError> {
+    let pending = pending(conn).await?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    conn.execute("
+    CREATE TABLE IF NOT EXISTS schema_version (
+        version                 INTEGER PRIMARY KEY,
+        description             MEDIUMTEXT,
+        applied_at              INTEGER
+    )
+    ", ()).await?;
+
+    for migration in pending {
+        println!("Applying migration {}: {}", migration.version, migration.description);
+
+        let transaction = conn.transaction().await?;
+        for step in migration.steps {
+            match step {
+                Step::Sql(sql) => {
+                    transaction.execute(sql, ()).await?;
+                },
+                Step::AddColumn(table, column, definition) => {
+                    if !has_column(&transaction, table, column).await? {
+                        transaction.execute(format!("ALTER TABLE {table} ADD COLUMN {column} {definition}").as_str(), ()).await?;
+                    }
+                }
+            }
+        }
+
+        let applied_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        transaction.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+            (migration.version, migration.description, applied_at)
+        ).await?;
+        transaction.commit().await?;
+    }
+
+    Ok(())
+}
+
+/// Prints what `run` would apply without changing anything.
+pub async fn print_pending(conn: &libsql::Connection) -> Result<(), MigrationError> {
+    let pending = pending(conn).await?;
+    if pending.is_empty() {
+        println!("Schema is up to date at version {}", latest_version());
+        return Ok(());
+    }
+
+    for migration in pending {
+        println!("Pending migration {}: {}", migration.version, migration.description);
+        for step in migration.steps {
+            println!("    {step}");
+        }
+    }
+    Ok(())
+}
+
+async fn has_column(conn: &libsql::Connection, table: &str, column: &str) -> Result<bool, libsql::Error> {
+    let mut rows = conn.query(format!("PRAGMA table_info({table})").as_str(), ()).await?;
+    while let Some(row) = rows.next().await? {
+        if row.get::<String>(1)? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsql::Builder;
+
+    async fn memory_connection() -> (libsql::Database, libsql::Connection) {
+        let db = Builder::new_local(":memory:").build().await.unwrap();
+        let conn = db.connect().unwrap();
+        (db, conn)
+    }
+
+    async fn count(conn: &libsql::Connection, query: &str) -> u64 {
+        conn.query(query, ()).await.unwrap().next().await.unwrap().unwrap().get(0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn running_twice_changes_nothing() {
+        let (_db, conn) = memory_connection().await;
+        run(&conn).await.unwrap();
+        run(&conn).await.unwrap();
+
+        assert_eq!(current_version(&conn).await.unwrap(), latest_version());
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM schema_version").await, MIGRATIONS.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn baseline_columns_are_added_to_an_old_database() {
+        let (_db, conn) = memory_connection().await;
+        // data.db as created before migrations existed, with the first columns only
+        conn.execute_batch("
+            CREATE TABLE users (id INTEGER PRIMARY KEY, username MEDIUMTEXT);
+            CREATE TABLE tracking_data (id INTEGER PRIMARY KEY AUTOINCREMENT, user_id INTEGER, time INTEGER, status TINYTEXT, activity MEDIUMTEXT, activity_description MEDIUMTEXT);
+            INSERT INTO users (id, username) VALUES (2, 'someone');
+            INSERT INTO tracking_data (user_id, time, status, activity, activity_description) VALUES (2, 100, 'online', 'Game', 'Unknown');
+        ").await.unwrap();
+
+        run(&conn).await.unwrap();
+
+        for column in ["desktop_status", "guild_id", "source", "job_id"] {
+            assert!(has_column(&conn, "tracking_data", column).await.unwrap(), "{column} is missing");
+        }
+        assert!(has_column(&conn, "users", "updated_at").await.unwrap());
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM tracking_data WHERE user_id = 2 AND guild_id IS NULL").await, 1);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM users").await, 1);
+    }
+
+    #[tokio::test]
+    async fn newer_databases_are_refused() {
+        let (_db, conn) = memory_connection().await;
+        run(&conn).await.unwrap();
+        conn.execute("INSERT INTO schema_version (version, description, applied_at) VALUES (?1, 'From the future', 0)", [latest_version() + 1]).await.unwrap();
+
+        let error = run(&conn).await.unwrap_err();
+        assert!(matches!(error, MigrationError::DatabaseNewer { database, binary } if database == latest_version() + 1 && binary == latest_version()));
+        assert!(matches!(print_pending(&conn).await, Err(MigrationError::DatabaseNewer { .. })));
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::error;
+use crate::store::Store;
+
+/// Tables of raw events that expire. Activities belong to `tracking_data` rows and are deleted along with them.
+/// Sessions, plays, custom statuses and identities are summaries and are kept.
+pub const RAW_TABLES: [&str; 4] = ["tracking_data", "voice_events", "message_events", "member_events"];
+
+const SECONDS_PER_DAY: u64 = 86400;
+const DEFAULT_BATCH_SIZE: u64 = 1000;
+const RETENTION_INTERVAL_SECONDS: u64 = 3600;
+/// Pause between batches so the writer is not starved of the database while a large backlog is pruned.
+const BATCH_PAUSE: Duration = Duration::from_millis(100);
+
+/// Times before which raw events are expired. `None` keeps them forever.
+#[derive(Debug)]
+pub struct Cutoffs {
+    pub default: Option<u64>,
+    /// Guilds with their own `retention_days`, which replaces the default for their rows.
+    pub guilds: Vec<(u64, Option<u64>)>
+}
+
+impl Cutoffs {
+    pub fn for_guild(&self, guild_id: Option<u64>) -> Option<u64> {
+        match guild_id.and_then(|guild_id| self.guilds.iter().find(|(id, _)| *id == guild_id)) {
+            Some((_, cutoff)) => *cutoff,
+            None => self.default
+        }
+    }
+}
+
+/// `RETENTION_DAYS` applies to every guild without its own `retention_days`. Unset or 0 keeps everything.
+pub fn default_days() -> Option<u64> {
+    env::var("RETENTION_DAYS").ok().and_then(|days| days.parse().ok()).filter(|days| *days > 0)
+}
+
+fn cutoff(now: u64, days: Option<u64>) -> Option<u64> {
+    days.filter(|days| *days > 0).map(|days| now.saturating_sub(days * SECONDS_PER_DAY))
+}
+
+/// `default_days` applies to guilds without their own `retention_days`, normally `default_days()`.
+/// A guild's `retention_days` of 0 keeps its rows forever even with a default set.
+pub async fn cutoffs(store: &dyn Store, now: u64, default_days: Option<u64>) -> Cutoffs {
+    Cutoffs {
+        default: cutoff(now, default_days),
+        guilds: store.get_guilds().await
+            .into_iter()
+            .filter_map(|guild| guild.retention_days.map(|days| (guild.guild_id, cutoff(now, Some(days)))))
+            .collect()
+    }
+}
+
+/// Deletes expired rows in batches of `RETENTION_BATCH_SIZE` and gives the freed pages back to the file system.
+pub async fn prune(store: &dyn Store, now: u64, default_days: Option<u64>) {
+    let cutoffs = cutoffs(store, now, default_days).await;
+    if cutoffs.default.is_none() && cutoffs.guilds.iter().all(|(_, cutoff)| cutoff.is_none()) {
+        return;
+    }
+
+    let batch_size: u64 = env::var("RETENTION_BATCH_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(DEFAULT_BATCH_SIZE).max(1);
+    let mut total = 0;
+    for table in RAW_TABLES {
+        let mut deleted = 0;
+        loop {
+            match store.delete_expired(table, &cutoffs, batch_size).await {
+                Ok(rows) => {
+                    deleted += rows;
+                    if rows < batch_size {
+                        break;
+                    }
+                },
+                Err(e) => {
+                    error!("Failed to prune {} {}", table, e);
+                    break;
+                }
+            }
+            tokio::time::sleep(BATCH_PAUSE).await;
+        }
+        if deleted > 0 {
+            println!("Pruned {deleted} expired rows from {table}");
+        }
+        total += deleted;
+    }
+
+    if total > 0 {
+        store.incremental_vacuum().await;
+    }
+}
+
+pub async fn retention_task(store: Arc<dyn Store>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(RETENTION_INTERVAL_SECONDS));
+    loop {
+        interval.tick().await;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        prune(store.as_ref(), now, default_days()).await;
+    }
+}
+
+/// Prints how many rows a prune at `now` would delete from every table, per guild, without deleting anything.
+pub async fn print_report(store: &dyn Store, now: u64) {
+    let cutoffs = cutoffs(store, now, default_days()).await;
+    match default_days() {
+        Some(days) => println!("Default retention: {days} days"),
+        None => println!("Default retention: keep forever")
+    }
+
+    let names: BTreeMap<u64, String> = store.get_guilds().await.into_iter().map(|guild| (guild.guild_id, guild.name)).collect();
+    let mut report: BTreeMap<Option<u64>, Vec<(&str, u64)>> = BTreeMap::new();
+    for table in RAW_TABLES {
+        for (guild_id, rows) in store.count_expired(table, &cutoffs).await {
+            report.entry(guild_id).or_default().push((table, rows));
+        }
+    }
+
+    if report.is_empty() {
+        println!("Nothing would be pruned");
+        return;
+    }
+
+    for (guild_id, tables) in report {
+        let guild = match guild_id {
+            Some(guild_id) => format!("{} ({guild_id})", names.get(&guild_id).map_or("Unknown guild", |name| name.as_str())),
+            None => String::from("Rows without a guild")
+        };
+        let breakdown = tables.iter().map(|(table, rows)| format!("{table}: {rows}")).collect::<Vec<String>>().join(", ");
+        println!("{guild} would lose {breakdown}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{MessageEvent, WriteJob};
+    use crate::fixtures;
+    use crate::memory::MemoryStore;
+
+    const NOW: u64 = 100 * SECONDS_PER_DAY;
+
+    fn message(guild_id: u64, days_ago: u64) -> WriteJob {
+        WriteJob::Message(MessageEvent { guild_id, ..fixtures::message(2, NOW - days_ago * SECONDS_PER_DAY) })
+    }
+
+    #[test]
+    fn zero_days_keeps_everything() {
+        assert_eq!(cutoff(NOW, Some(0)), None);
+        assert_eq!(cutoff(NOW, None), None);
+        assert_eq!(cutoff(NOW, Some(30)), Some(70 * SECONDS_PER_DAY));
+    }
+
+    #[test]
+    fn guild_cutoffs_replace_the_default() {
+        let cutoffs = Cutoffs { default: Some(10), guilds: vec!((1, Some(20)), (2, None)) };
+        assert_eq!(cutoffs.for_guild(Some(1)), Some(20));
+        assert_eq!(cutoffs.for_guild(Some(2)), None);
+        assert_eq!(cutoffs.for_guild(Some(3)), Some(10));
+        assert_eq!(cutoffs.for_guild(None), Some(10));
+    }
+
+    #[tokio::test]
+    async fn prune_deletes_only_expired_rows() {
+        let store = MemoryStore::default();
+        store.set_retention_days(1, Some(30)).await;
+        store.write_batch(vec!(message(1, 40), message(1, 10), message(2, 40))).await;
+
+        // Without a default, guilds without their own setting keep everything
+        let cutoffs = cutoffs(&store, NOW, None).await;
+        assert_eq!(store.count_expired("message_events", &cutoffs).await, vec!((Some(1), 1)));
+
+        prune(&store, NOW, None).await;
+        assert_eq!(store.get_message_counts_by_user(Some("1"), None, 10).await, vec!((2, 1)));
+        assert_eq!(store.get_message_counts_by_user(Some("2"), None, 10).await, vec!((2, 1)));
+        assert!(store.count_expired("message_events", &cutoffs).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn default_days_apply_to_guilds_without_their_own() {
+        let store = MemoryStore::default();
+        store.set_retention_days(1, Some(0)).await;
+        store.write_batch(vec!(message(1, 40), message(2, 40), message(2, 10))).await;
+
+        prune(&store, NOW, Some(30)).await;
+        assert_eq!(store.get_message_counts_by_user(Some("1"), None, 10).await, vec!((2, 1)));
+        assert_eq!(store.get_message_counts_by_user(Some("2"), None, 10).await, vec!((2, 1)));
+    }
+}
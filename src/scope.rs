@@ -0,0 +1,211 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use crate::store::{Store, StoreError};
+
+/// Which members of a guild get recorded. Stored in `tracking_rules` and the `guilds.track_bots` column.
+#[derive(Debug, Default, Clone)]
+pub struct TrackingRules {
+    pub track_bots: bool,
+    pub include_roles: Vec<u64>,
+    pub exclude_roles: Vec<u64>,
+    pub allow_users: Vec<u64>,
+    pub deny_users: Vec<u64>
+}
+
+impl TrackingRules {
+    /// Explicit user lists win over everything else, then bots are dropped, then excluded roles,
+    /// and finally members need one of the included roles if there are any.
+    /// `roles` is `None` when the member is not cached, in which case role rules cannot match.
+    pub fn allows(&self, user_id: u64, is_bot: bool, roles: Option<&[u64]>) -> bool {
+        if self.deny_users.contains(&user_id) {
+            return false;
+        }
+        if self.allow_users.contains(&user_id) {
+            return true;
+        }
+        if is_bot && !self.track_bots {
+            return false;
+        }
+
+        let roles = roles.unwrap_or_default();
+        if roles.iter().any(|role| self.exclude_roles.contains(role)) {
+            return false;
+        }
+        if !self.include_roles.is_empty() && !roles.iter().any(|role| self.include_roles.contains(role)) {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Enabled guilds, opt outs and tracking rules, read from the store once instead of on every event.
+/// The commands that edit them invalidate what they changed, so edits still apply without a restart.
+#[derive(Default)]
+pub struct ScopeCache {
+    cached: Mutex<Cached>
+}
+
+#[derive(Default)]
+struct Cached {
+    /// Bumped by every invalidation, so a lookup that raced one does not keep what it read
+    generation: u64,
+    enabled: HashMap<u64, bool>,
+    opted_out: Option<HashSet<u64>>,
+    rules: HashMap<u64, TrackingRules>
+}
+
+impl ScopeCache {
+    fn cached(&self) -> std::sync::MutexGuard<'_, Cached> {
+        self.cached.lock().unwrap()
+    }
+
+    pub async fn is_guild_enabled(&self, store: &dyn Store, guild_id: u64) -> Result<bool, StoreError> {
+        let generation = {
+            let cached = self.cached();
+            if let Some(enabled) = cached.enabled.get(&guild_id) {
+                return Ok(*enabled);
+            }
+            cached.generation
+        };
+
+        let enabled = store.is_guild_enabled(guild_id).await?;
+        let mut cached = self.cached();
+        if cached.generation == generation {
+            cached.enabled.insert(guild_id, enabled);
+        }
+        Ok(enabled)
+    }
+
+    pub async fn is_opted_out(&self, store: &dyn Store, user_id: u64) -> Result<bool, StoreError> {
+        if let Some(opted_out) = &self.cached().opted_out {
+            return Ok(opted_out.contains(&user_id));
+        }
+        Ok(self.opted_out(store).await?.contains(&user_id))
+    }
+
+    pub async fn opted_out(&self, store: &dyn Store) -> Result<HashSet<u64>, StoreError> {
+        let generation = {
+            let cached = self.cached();
+            if let Some(opted_out) = &cached.opted_out {
+                return Ok(opted_out.clone());
+            }
+            cached.generation
+        };
+
+        let opted_out = store.get_opted_out().await?;
+        let mut cached = self.cached();
+        if cached.generation == generation {
+            cached.opted_out = Some(opted_out.clone());
+        }
+        Ok(opted_out)
+    }
+
+    pub async fn tracking_rules(&self, store: &dyn Store, guild_id: u64) -> Result<TrackingRules, StoreError> {
+        let generation = {
+            let cached = self.cached();
+            if let Some(rules) = cached.rules.get(&guild_id) {
+                return Ok(rules.clone());
+            }
+            cached.generation
+        };
+
+        let rules = store.get_tracking_rules(guild_id).await?;
+        let mut cached = self.cached();
+        if cached.generation == generation {
+            cached.rules.insert(guild_id, rules.clone());
+        }
+        Ok(rules)
+    }
+
+    /// After the guild was enabled or disabled or its rules changed.
+    pub fn invalidate_guild(&self, guild_id: u64) {
+        let mut cached = self.cached();
+        cached.generation += 1;
+        cached.enabled.remove(&guild_id);
+        cached.rules.remove(&guild_id);
+    }
+
+    /// After someone opted out or back in.
+    pub fn invalidate_opt_outs(&self) {
+        let mut cached = self.cached();
+        cached.generation += 1;
+        cached.opted_out = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+
+    #[test]
+    fn everyone_is_allowed_without_rules() {
+        let rules = TrackingRules::default();
+        assert!(rules.allows(1, false, Some(&[])));
+        assert!(rules.allows(1, false, None));
+    }
+
+    #[test]
+    fn bots_need_track_bots() {
+        let mut rules = TrackingRules::default();
+        assert!(!rules.allows(1, true, Some(&[])));
+
+        rules.track_bots = true;
+        assert!(rules.allows(1, true, Some(&[])));
+    }
+
+    #[test]
+    fn user_lists_win_over_roles_and_bots() {
+        let rules = TrackingRules {
+            include_roles: vec!(10),
+            allow_users: vec!(1),
+            deny_users: vec!(2),
+            ..Default::default()
+        };
+        assert!(rules.allows(1, true, Some(&[])));
+        assert!(!rules.allows(2, false, Some(&[10])));
+    }
+
+    #[test]
+    fn excluded_roles_win_over_included_ones() {
+        let rules = TrackingRules {
+            include_roles: vec!(10),
+            exclude_roles: vec!(20),
+            ..Default::default()
+        };
+        assert!(rules.allows(1, false, Some(&[10])));
+        assert!(!rules.allows(1, false, Some(&[10, 20])));
+        assert!(!rules.allows(1, false, Some(&[30])));
+    }
+
+    #[test]
+    fn uncached_members_match_no_included_role() {
+        let rules = TrackingRules {
+            include_roles: vec!(10),
+            ..Default::default()
+        };
+        assert!(!rules.allows(1, false, None));
+    }
+
+    #[tokio::test]
+    async fn cached_scope_changes_after_invalidation() {
+        let store = MemoryStore::default();
+        let cache = ScopeCache::default();
+
+        assert!(!cache.is_guild_enabled(&store, 1).await.unwrap());
+        store.set_guild_enabled(1, true).await;
+        store.set_track_bots(1, true).await;
+        assert!(!cache.is_guild_enabled(&store, 1).await.unwrap());
+        cache.invalidate_guild(1);
+        assert!(cache.is_guild_enabled(&store, 1).await.unwrap());
+        assert!(cache.tracking_rules(&store, 1).await.unwrap().track_bots);
+
+        store.set_opted_out(2, true, 100).await;
+        assert!(cache.is_opted_out(&store, 2).await.unwrap());
+        store.set_opted_out(2, false, 200).await;
+        assert!(cache.is_opted_out(&store, 2).await.unwrap());
+        cache.invalidate_opt_outs();
+        assert!(!cache.is_opted_out(&store, 2).await.unwrap());
+    }
+}
@@ -0,0 +1,181 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::error;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use crate::database::WriteJob;
+
+/// A line of the spool. `job_id` ends up in the written row so replaying the same line twice is a no-op.
+#[derive(Serialize, Deserialize)]
+pub struct SpooledJob {
+    pub job_id: String,
+    pub job: WriteJob
+}
+
+/// Append-only JSON lines file for write jobs that could not be written or did not fit in the write queue.
+/// Stored at `SPOOL_PATH`, `spool.jsonl` by default. While a replay is running its jobs live in a `.replaying` file next to it.
+/// Appends are handed to a thread that keeps the file open, so spooling never blocks the runtime.
+pub struct Spool {
+    path: PathBuf,
+    replaying: PathBuf,
+    /// Open while there is a spool file. `take` closes it before moving the file aside.
+    file: Arc<Mutex<Option<File>>>,
+    appends: Sender<Append>
+}
+
+enum Append {
+    Lines(String, usize),
+    /// Answered once everything sent before it is on disk.
+    Flush(Sender<()>)
+}
+
+impl Spool {
+    pub fn from_env() -> Self {
+        let path = PathBuf::from(env::var("SPOOL_PATH").unwrap_or(String::from("spool.jsonl")));
+        let mut replaying = path.clone().into_os_string();
+        replaying.push(".replaying");
+
+        let file = Arc::new(Mutex::new(None));
+        let (appends, rx) = mpsc::channel();
+        let thread_path = path.clone();
+        let thread_file = Arc::clone(&file);
+        thread::spawn(move || {
+            for append in rx {
+                match append {
+                    Append::Lines(lines, count) => write_lines(&thread_path, &thread_file, &lines, count),
+                    Append::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+
+        Spool {
+            path,
+            replaying: PathBuf::from(replaying),
+            file,
+            appends
+        }
+    }
+
+    /// Blocks until every job appended so far is written, for the end of a shutdown.
+    pub fn flush(&self) {
+        let (done, rx) = mpsc::channel();
+        if self.appends.send(Append::Flush(done)).is_ok() {
+            let _ = rx.recv();
+        }
+    }
+
+    /// Gives every job a new id and appends it.
+    pub fn append(&self, jobs: Vec<WriteJob>) {
+        let spooled: Vec<SpooledJob> = jobs
+            .into_iter()
+            .map(|job| SpooledJob { job_id: new_job_id(), job })
+            .collect();
+        self.append_spooled(&spooled);
+    }
+
+    /// Appends jobs that already have an id, such as ones that failed again during a replay.
+    pub fn append_spooled(&self, jobs: &[SpooledJob]) {
+        if jobs.is_empty() {
+            return;
+        }
+
+        let mut lines = String::new();
+        for spooled in jobs {
+            match serde_json::to_string(spooled) {
+                Ok(line) => {
+                    lines.push_str(&line);
+                    lines.push('\n');
+                },
+                Err(e) => error!("Could not serialize job {} for the spool {}", spooled.job_id, e)
+            }
+        }
+
+        if self.appends.send(Append::Lines(lines, jobs.len())).is_err() {
+            error!("Spool thread is gone, {} jobs are lost", jobs.len());
+        }
+    }
+
+    /// Returns the jobs to replay. A replay that did not finish is picked up again before new jobs are moved aside.
+    pub fn take(&self) -> Option<Vec<SpooledJob>> {
+        let mut file = self.file.lock().unwrap();
+
+        if !self.replaying.exists() {
+            if !self.path.exists() {
+                return None;
+            }
+            // Later appends reopen the path and start a new spool file
+            *file = None;
+            if let Err(e) = fs::rename(&self.path, &self.replaying) {
+                error!("Could not move the spool aside for replay {}", e);
+                return None;
+            }
+        }
+
+        let content = match fs::read_to_string(&self.replaying) {
+            Ok(content) => content,
+            Err(e) => {
+                error!("Could not read the spool {}", e);
+                return None;
+            }
+        };
+
+        let jobs: Vec<SpooledJob> = content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(spooled) => Some(spooled),
+                Err(e) => {
+                    error!("Skipping unreadable spool line {}", e);
+                    None
+                }
+            })
+            .collect();
+
+        Some(jobs)
+    }
+
+    /// Called once the jobs returned by `take` are committed.
+    pub fn finish(&self) {
+        let _guard = self.file.lock().unwrap();
+        if let Err(e) = fs::remove_file(&self.replaying) {
+            error!("Could not remove the replayed spool {}", e);
+        }
+    }
+}
+
+fn write_lines(path: &Path, file: &Mutex<Option<File>>, lines: &str, count: usize) {
+    let mut file = file.lock().unwrap();
+    if file.is_none() {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(opened) => *file = Some(opened),
+            Err(e) => {
+                error!("Could not open the spool, {} jobs are lost {}", count, e);
+                return;
+            }
+        }
+    }
+
+    if let Some(opened) = file.as_mut() {
+        match opened.write_all(lines.as_bytes()) {
+            Ok(()) => println!("Spooled {} write jobs", count),
+            Err(e) => {
+                error!("Could not append {} jobs to the spool, they are lost {}", count, e);
+                // Reopened on the next append in case the file went away
+                *file = None;
+            }
+        }
+    }
+}
+
+fn new_job_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    format!("{:x}-{:016x}", nanos, rand::rng().random::<u64>())
+}
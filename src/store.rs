@@ -0,0 +1,413 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use log::error;
+use poise::serenity_prelude as serenity;
+use tokio::sync::Notify;
+use tokio::sync::mpsc::{Sender, Receiver, channel};
+use crate::database::{
+    ActivityRecord, CoveragePeriod, CustomStatusChange, DataFilter, Database, GuildConfig, MemberEvent,
+    PresenceRow, Session, SpotifyPlay, VoiceEvent, WriteJob
+};
+use crate::memory::MemoryStore;
+use crate::migrations::MigrationError;
+use crate::retention::Cutoffs;
+use crate::scope::TrackingRules;
+use crate::spool::{Spool, SpooledJob};
+
+pub type StoreError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Everything the handler, the writer and the webserver read or write. `Database` keeps it in the SQLite file,
+/// `MemoryStore` only for the lifetime of the process.
+#[serenity::async_trait]
+pub trait Store: Send + Sync {
+    async fn add_tracking_rule(&self, guild_id: u64, kind: &str, target_id: u64);
+    /// Returns whether a rule was removed.
+    async fn remove_tracking_rule(&self, guild_id: u64, kind: &str, target_id: u64) -> bool;
+    async fn set_track_bots(&self, guild_id: u64, track_bots: bool);
+    /// Cached by `ScopeCache`, which the commands editing rules invalidate.
+    async fn get_tracking_rules(&self, guild_id: u64) -> Result<TrackingRules, StoreError>;
+
+    /// Remembers a guild the bot is in. Newly seen guilds start out disabled.
+    async fn register_guild(&self, guild_id: u64, name: &str);
+    async fn set_guild_enabled(&self, guild_id: u64, enabled: bool);
+    async fn is_guild_enabled(&self, guild_id: u64) -> Result<bool, StoreError>;
+    async fn get_guilds(&self) -> Vec<GuildConfig>;
+    /// `None` puts the guild back on `RETENTION_DAYS`.
+    async fn set_retention_days(&self, guild_id: u64, days: Option<u64>);
+
+    /// Rows of a raw event table that are past their cutoff, counted per guild.
+    async fn count_expired(&self, table: &str, cutoffs: &Cutoffs) -> Vec<(Option<u64>, u64)>;
+    /// Deletes up to `limit` expired rows of a raw event table and returns how many went.
+    async fn delete_expired(&self, table: &str, cutoffs: &Cutoffs, limit: u64) -> Result<u64, StoreError>;
+    /// Gives the space freed by deleted rows back to the file system.
+    async fn incremental_vacuum(&self);
+
+    /// Starts a coverage period for the shard, ending any period it still had open.
+    async fn open_coverage(&self, shard_id: u32, reason: &str, time: u64);
+    /// Ends the open coverage period of one shard, or of every shard when `shard_id` is `None`.
+    async fn close_coverage(&self, shard_id: Option<u32>, reason: &str, time: u64);
+    /// Heartbeat for the open coverage periods, so a crash can be dated to the last moment the bot was alive.
+    async fn touch_coverage(&self, time: u64);
+    /// Periods still open at startup belong to a process that died without closing them. They end at their last heartbeat.
+    async fn close_abandoned_coverage(&self);
+    async fn get_coverage(&self, limit: u64) -> Vec<CoveragePeriod>;
+    /// Intervals between `from` and `to` during which no shard was connected, as `(start, end)`.
+    async fn get_coverage_gaps(&self, from: u64, to: u64, now: u64) -> Vec<(u64, u64)>;
+
+    /// Records the start of this process and returns its run id. A previous run without the clean shutdown
+    /// marker crashed or was killed, and is closed at the last coverage heartbeat.
+    async fn start_run(&self, time: u64) -> Result<u64, StoreError>;
+    /// Written last, once the write queue has been flushed.
+    async fn finish_run(&self, run_id: u64, time: u64);
+
+    async fn set_opted_out(&self, user_id: u64, opted_out: bool, time: u64);
+    /// Everyone who opted out. Cached by `ScopeCache`, which /optout and /optin invalidate.
+    async fn get_opted_out(&self) -> Result<HashSet<u64>, StoreError>;
+    /// Deletes everything recorded about a user at once. Returns how many rows each table lost.
+    async fn forget_user(&self, user_id: u64) -> Result<Vec<(&'static str, u64)>, StoreError>;
+
+    /// The name each `(guild_id, user_id, time)` was shown with at that time: nickname, then display name, then username.
+    /// Events from before the first recorded identity use the oldest one; users without any history use `get_usernames`.
+    async fn get_names_at(&self, lookups: Vec<(u64, u64, u64)>) -> HashMap<(u64, u64, u64), String>;
+    /// The last known username of a single user, `None` if they were never seen.
+    async fn get_username(&self, user_id: u64) -> Result<Option<String>, StoreError>;
+    /// Users that were never seen are left out.
+    async fn get_usernames(&self, ids: Vec<u64>) -> HashMap<u64, String>;
+
+    /// Writes the jobs together and returns the ones that were not written, for the spool.
+    async fn write_batch(&self, jobs: Vec<WriteJob>) -> Vec<WriteJob>;
+    /// Writes spooled jobs, skipping the ones whose `job_id` was already written. Returns the jobs that failed on their own;
+    /// an error means nothing was written and the whole replay has to be retried.
+    async fn replay(&self, jobs: Vec<SpooledJob>) -> Result<Vec<SpooledJob>, StoreError>;
+    /// Sessions still open at startup were cut off by a restart. They are closed at the end of the last coverage period,
+    /// or at the last moment the bot wrote anything when there is no coverage yet.
+    async fn close_stale_sessions(&self);
+
+    /// A page of presences for the dashboard.
+    async fn get_data(&self, page: &u64, filter: &DataFilter<'_>) -> Vec<PresenceRow>;
+    /// Query plan of the dashboard query as `(id, parent, detail)` rows, to see which filters scan the table.
+    async fn explain_data_query(&self, page: &u64, filter: &DataFilter<'_>) -> Result<Vec<(i64, i64, String)>, StoreError>;
+    /// Every activity recorded for the given presences, keyed by presence id and ordered by position.
+    async fn get_activities(&self, tracking_ids: Vec<u64>) -> HashMap<u64, Vec<ActivityRecord>>;
+    async fn get_recent_plays(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<SpotifyPlay>;
+    /// Most played artists as `(artist, plays)`.
+    async fn get_top_artists(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<(String, u64)>;
+    /// Most played tracks as `(title, artist, plays)`.
+    async fn get_top_tracks(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<(String, String, u64)>;
+    async fn get_sessions(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<Session>;
+    /// Total seconds spent in each activity as `(activity, seconds)`. Open sessions count up to `now`, and time a user
+    /// spent in the same activity while tracked in several guilds counts once.
+    async fn get_activity_totals(&self, guild_id: Option<&str>, user_id: Option<&str>, now: u64, limit: u64) -> Vec<(String, u64)>;
+    async fn get_voice_events(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<VoiceEvent>;
+    /// Messages sent per user as `(user_id, messages)`.
+    async fn get_message_counts_by_user(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<(u64, u64)>;
+    /// Messages sent per channel as `(channel_name, messages)`, using the latest known name of each channel.
+    async fn get_message_counts_by_channel(&self, guild_id: Option<&str>, user_id: Option<&str>, limit: u64) -> Vec<(String, u64)>;
+    /// Member events of the given users between `from` and `to`, oldest first.
+    async fn get_member_events(&self, guild_id: Option<&str>, user_ids: Vec<u64>, from: u64, to: u64) -> Vec<MemberEvent>;
+    async fn get_custom_status_history(&self, guild_id: Option<&str>, user_id: Option<&str>, search: Option<&str>, limit: u64) -> Vec<CustomStatusChange>;
+}
+
+/// `DATABASE_PATH`, `data.db` by default.
+pub fn database_path() -> String {
+    env::var("DATABASE_PATH").unwrap_or(String::from("data.db"))
+}
+
+/// Opens the store selected by `STORE`: `sqlite` for the file at `database_path`, which is the default,
+/// or `memory` for a store that forgets everything on exit.
+pub async fn open_from_env() -> Result<Arc<dyn Store>, MigrationError> {
+    match env::var("STORE").as_deref() {
+        Ok("memory") => {
+            println!("Keeping everything in memory, nothing will be saved");
+            Ok(Arc::new(MemoryStore::default()))
+        },
+        Ok("sqlite") | Err(_) => Ok(Arc::new(Database::open(&database_path()).await?)),
+        Ok(other) => {
+            println!("Unknown STORE {other}, using sqlite");
+            Ok(Arc::new(Database::open(&database_path()).await?))
+        }
+    }
+}
+
+/// `WRITE_QUEUE_SIZE` jobs, never fewer than a batch so a full queue can always be flushed at once.
+pub fn new_write_queue() -> (Sender<WriteJob>, Receiver<WriteJob>) {
+    let buffer: usize = env::var("WRITE_QUEUE_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(DEFAULT_QUEUE_SIZE);
+    channel(buffer.max(batch_size()))
+}
+
+/// `WRITE_BATCH_SIZE`, at least 1.
+pub fn batch_size() -> usize {
+    env::var("WRITE_BATCH_SIZE").ok().and_then(|size| size.parse().ok()).unwrap_or(DEFAULT_BATCH_SIZE).max(1)
+}
+
+/// Drains the write queue in batches. `stop` closes the queue. Jobs already in it are still written,
+/// later ones are refused and end up in the spool.
+pub async fn writer_task(store: Arc<dyn Store>, mut rx: Receiver<WriteJob>, spool: Arc<Spool>, stop: Arc<Notify>) {
+    store.close_stale_sessions().await;
+
+    let batch_size = batch_size();
+    let flush_interval = Duration::from_millis(env::var("WRITE_FLUSH_MS").ok().and_then(|ms| ms.parse().ok()).unwrap_or(DEFAULT_FLUSH_MS));
+    let mut batch: Vec<WriteJob> = Vec::with_capacity(batch_size);
+
+    // A batch starts with the first job that arrives and is flushed once it is full or the interval ran out.
+    // recv only returns None once the queue is closed and empty, so nothing is left behind on shutdown.
+    loop {
+        let job = tokio::select! {
+            job = rx.recv() => job,
+            _ = stop.notified() => {
+                rx.close();
+                rx.recv().await
+            }
+        };
+        let Some(job) = job else {
+            break;
+        };
+        batch.push(job);
+        let deadline = tokio::time::Instant::now() + flush_interval;
+
+        while batch.len() < batch_size {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(job)) => batch.push(job),
+                Ok(None) | Err(_) => break
+            }
+        }
+
+        let unwritten = store.write_batch(std::mem::take(&mut batch)).await;
+        if !unwritten.is_empty() {
+            spool.append(unwritten);
+        }
+    }
+    println!("Write queue closed, writer stopped");
+}
+
+const DEFAULT_QUEUE_SIZE: usize = 2000;
+const DEFAULT_BATCH_SIZE: usize = 500;
+const DEFAULT_FLUSH_MS: u64 = 250;
+
+const SPOOL_REPLAY_SECONDS: u64 = 30;
+
+/// Periodically writes spooled jobs back. The spool file is only removed once the store took its jobs,
+/// and a crash in between is harmless because already written jobs are ignored by their `job_id`.
+pub async fn replay_task(store: Arc<dyn Store>, spool: Arc<Spool>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(SPOOL_REPLAY_SECONDS));
+
+    loop {
+        interval.tick().await;
+
+        let Some(jobs) = spool.take() else {
+            continue;
+        };
+        let size = jobs.len();
+        match store.replay(jobs).await {
+            Ok(failed) => {
+                // Jobs that failed on their own are appended again with their ids and the replayed file is done
+                spool.append_spooled(&failed);
+                spool.finish();
+                println!("Replayed {} spooled jobs, {} failed", size - failed.len(), failed.len());
+            },
+            Err(e) => error!("Spool replay failed, retrying later {}", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{PresenceJob, SpotifyPlay};
+    use crate::fixtures::{self, GUILD};
+    use crate::retention::Cutoffs;
+
+    fn presence(user_id: u64, time: u64, status: &str, activity: Option<&str>) -> WriteJob {
+        WriteJob::Presence(Box::new(fixtures::presence(user_id, time, status, activity)))
+    }
+
+    fn listening(user_id: u64, time: u64, play: SpotifyPlay) -> WriteJob {
+        WriteJob::Presence(Box::new(PresenceJob { spotify: Some(play), ..fixtures::presence(user_id, time, "online", Some("Spotify")) }))
+    }
+
+    fn message(user_id: u64, time: u64) -> WriteJob {
+        WriteJob::Message(fixtures::message(user_id, time))
+    }
+
+    fn identity(user_id: u64, username: &str) -> WriteJob {
+        identity_at(user_id, username, 100)
+    }
+
+    fn identity_at(user_id: u64, username: &str, time: u64) -> WriteJob {
+        WriteJob::Identity(fixtures::identity(user_id, username, time))
+    }
+
+    fn spooled(job_id: &str, job: WriteJob) -> SpooledJob {
+        SpooledJob { job_id: String::from(job_id), job }
+    }
+
+    async fn write_batch_stores_every_job(store: &dyn Store) {
+        let unwritten = store.write_batch(vec!(
+            identity(2, "someone"),
+            presence(2, 100, "online", Some("Game")),
+            message(2, 110)
+        )).await;
+        assert!(unwritten.is_empty());
+
+        let rows = store.get_data(&1, &DataFilter::default()).await;
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].status, "online");
+        assert_eq!(store.get_activities(vec!(rows[0].id)).await[&rows[0].id][0].name, "Game");
+        assert_eq!(store.get_message_counts_by_user(None, None, 10).await, vec!((2, 1)));
+        assert_eq!(store.get_username(2).await.unwrap().as_deref(), Some("someone"));
+    }
+
+    async fn replayed_jobs_are_written_once(store: &dyn Store) {
+        store.replay(vec!(spooled("a", presence(2, 100, "online", None)), spooled("b", message(2, 100)))).await.unwrap();
+        store.replay(vec!(spooled("a", presence(2, 100, "online", None)), spooled("b", message(2, 100)))).await.unwrap();
+
+        assert_eq!(store.get_data(&1, &DataFilter::default()).await.len(), 1);
+        assert_eq!(store.get_message_counts_by_user(None, None, 10).await, vec!((2, 1)));
+    }
+
+    async fn sessions_follow_status_and_activities(store: &dyn Store) {
+        store.write_batch(vec!(
+            presence(2, 100, "online", Some("Game")),
+            presence(2, 200, "online", None),
+            presence(2, 300, "idle", None)
+        )).await;
+
+        let sessions = store.get_sessions(None, None, 10).await;
+        let find = |value: &str| sessions.iter().find(|s| s.value == value).unwrap();
+        assert_eq!((find("online").started_at, find("online").ended_at), (100, Some(300)));
+        assert_eq!((find("Game").started_at, find("Game").ended_at), (100, Some(200)));
+        assert_eq!((find("idle").started_at, find("idle").ended_at), (300, None));
+        assert_eq!(store.get_activity_totals(None, None, 1000, 10).await, vec!((String::from("Game"), 100)));
+    }
+
+    async fn older_presences_leave_sessions_alone(store: &dyn Store) {
+        store.write_batch(vec!(presence(2, 300, "idle", None))).await;
+        // A spooled presence from before the live one arrives late
+        store.replay(vec!(spooled("late", presence(2, 100, "online", Some("Game"))))).await.unwrap();
+
+        assert_eq!(store.get_data(&1, &DataFilter::default()).await.len(), 2);
+        let sessions = store.get_sessions(None, None, 10).await;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!((sessions[0].value.as_str(), sessions[0].started_at, sessions[0].ended_at), ("idle", 300, None));
+    }
+
+    async fn forget_user_removes_only_that_user(store: &dyn Store) {
+        store.write_batch(vec!(
+            identity(2, "forgotten"),
+            presence(2, 100, "online", Some("Game")),
+            message(2, 100),
+            identity(3, "kept"),
+            presence(3, 100, "online", None),
+            message(3, 100)
+        )).await;
+
+        let removed = store.forget_user(2).await.unwrap();
+        let count = |table: &str| removed.iter().find(|(t, _)| *t == table).map(|(_, rows)| *rows);
+        assert_eq!(count("tracking_data"), Some(1));
+        assert_eq!(count("presence_activities"), Some(1));
+        assert_eq!(count("message_events"), Some(1));
+        assert_eq!(count("users"), Some(1));
+
+        let rows = store.get_data(&1, &DataFilter::default()).await;
+        assert!(rows.iter().all(|row| row.user_id == 3));
+        assert_eq!(rows.len(), 1);
+        assert!(store.get_sessions(Some("1"), Some("2"), 10).await.is_empty());
+        assert_eq!(store.get_username(2).await.unwrap(), None);
+        assert_eq!(store.get_usernames(vec!(2, 3)).await, HashMap::from([(3, String::from("kept"))]));
+        assert_eq!(store.get_username(3).await.unwrap().as_deref(), Some("kept"));
+    }
+
+    async fn stale_identities_do_not_replace_newer_ones(store: &dyn Store) {
+        store.write_batch(vec!(identity_at(2, "new", 300))).await;
+        let stale = || vec!(spooled("old", identity_at(2, "old", 100)), spooled("same", identity_at(2, "new", 200)));
+        store.replay(stale()).await.unwrap();
+        store.replay(stale()).await.unwrap();
+
+        assert_eq!(store.get_username(2).await.unwrap().as_deref(), Some("new"));
+        assert_eq!(store.get_names_at(vec!((GUILD, 2, 400))).await[&(GUILD, 2, 400)], "new");
+    }
+
+    async fn spotify_plays_continue_until_the_track_changes(store: &dyn Store) {
+        store.write_batch(vec!(
+            listening(2, 110, fixtures::spotify_play(2, "a", 100, Some(400))),
+            // Seeking moves the end of the same play
+            listening(2, 200, fixtures::spotify_play(2, "a", 160, Some(460))),
+            listening(2, 460, fixtures::spotify_play(2, "b", 460, Some(700))),
+            // Playing the first track again is a new play
+            listening(2, 700, fixtures::spotify_play(2, "a", 700, Some(1000)))
+        )).await;
+
+        let plays = store.get_recent_plays(None, None, 10).await;
+        let plays: Vec<(&str, u64, Option<u64>)> = plays.iter().map(|play| (play.track_id.as_str(), play.started_at, play.ends_at)).collect();
+        assert_eq!(plays, vec!(("a", 700, Some(1000)), ("b", 460, Some(700)), ("a", 100, Some(460))));
+        assert_eq!(store.get_top_tracks(None, None, 10).await[0], (String::from("Title of a"), String::from("Artist"), 2));
+    }
+
+    async fn expired_rows_are_deleted_with_their_activities(store: &dyn Store) {
+        store.write_batch(vec!(
+            presence(2, 100, "online", Some("Game")),
+            presence(2, 300, "online", Some("Game")),
+            WriteJob::Presence(Box::new(PresenceJob { guild_id: 2, ..fixtures::presence(3, 100, "online", None) })),
+            message(2, 100),
+            message(2, 150)
+        )).await;
+        let expired_id = store.get_data(&1, &DataFilter::default()).await
+            .into_iter()
+            .find(|row| row.guild_id == Some(GUILD) && row.time == 100)
+            .unwrap()
+            .id;
+
+        // Guild 2 keeps everything, the default applies to the others
+        let cutoffs = Cutoffs { default: Some(200), guilds: vec!((2, None)) };
+        assert_eq!(store.count_expired("tracking_data", &cutoffs).await, vec!((Some(GUILD), 1)));
+        assert_eq!(store.delete_expired("tracking_data", &cutoffs, 10).await.unwrap(), 1);
+        assert!(store.get_activities(vec!(expired_id)).await.is_empty());
+
+        let mut kept: Vec<(u64, u64)> = store.get_data(&1, &DataFilter::default()).await.iter().map(|row| (row.user_id, row.time)).collect();
+        kept.sort();
+        assert_eq!(kept, vec!((2, 300), (3, 100)));
+
+        assert_eq!(store.delete_expired("message_events", &cutoffs, 1).await.unwrap(), 1);
+        assert_eq!(store.delete_expired("message_events", &cutoffs, 1).await.unwrap(), 1);
+        assert_eq!(store.delete_expired("message_events", &cutoffs, 1).await.unwrap(), 0);
+        assert!(store.count_expired("tracking_data", &cutoffs).await.is_empty());
+    }
+
+    /// Runs every scenario against both backends, so the in-memory copy is held to what SQLite does.
+    macro_rules! on_every_store {
+        ($($scenario:ident),* $(,)?) => {
+            mod memory {
+                use super::*;
+                $(
+                    #[tokio::test]
+                    async fn $scenario() {
+                        super::$scenario(&MemoryStore::default()).await;
+                    }
+                )*
+            }
+
+            mod sqlite {
+                use super::*;
+                $(
+                    #[tokio::test]
+                    async fn $scenario() {
+                        super::$scenario(&Database::open(":memory:").await.unwrap()).await;
+                    }
+                )*
+            }
+        };
+    }
+
+    on_every_store!(
+        write_batch_stores_every_job,
+        replayed_jobs_are_written_once,
+        sessions_follow_status_and_activities,
+        older_presences_leave_sessions_alone,
+        forget_user_removes_only_that_user,
+        stale_identities_do_not_replace_newer_ones,
+        spotify_plays_continue_until_the_track_changes,
+        expired_rows_are_deleted_with_their_activities
+    );
+}
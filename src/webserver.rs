@@ -1,69 +1,138 @@
-use rouille;
 use rouille::router;
-use serenity::all::Route;
 use std::collections::HashMap;
-use std::fs;
-use std::env;
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
 use crate::database;
+use crate::store::Store;
 use crate::futures::executor;
 use chrono::prelude::{DateTime};
 
-struct DatabaseTarget {
-    id: u64,
-    user_id: u64,
-    time: u64,
-    status: String,
-    activity: String,
-    activity_description: String,
-}
-
-#[allow(unreachable_code)]
-
-pub fn main(key: String) {
-    let key: String = key;
+/// Starts the dashboard on its own thread. Sending on the returned channel stops it, and the handle finishes once it has.
+pub fn start(key: String, store: Arc<dyn Store>) -> (JoinHandle<()>, mpsc::Sender<()>) {
     println!("Now listening on 0.0.0.0:8000");
 
-    rouille::start_server("0.0.0.0:8000", move |request| {
+    let server = rouille::Server::new("0.0.0.0:8000", move |request| {
         router!(request,
             (GET) (/) => {
                 let cookies = parse_cookies(request);
 
-                if let Some(auth_token) = cookies.get("Authorization") {
-                    if auth_token != &key {
-                        return rouille::Response::redirect_302("/login#invalid");
-                    } 
-                } else {
-                    return rouille::Response::redirect_302("/login#not-specified");
+                if let Some(redirect) = require_login(&cookies, &key) {
+                    return redirect;
                 }
                 
-                
-                
                 let page_number: u64 = cookies.get("page").unwrap_or(&String::from("1")).parse().unwrap();
 
-                let data = executor::block_on(retrieve_data_from_db(
-                    page_number,
-                    cookies.get("userId").map(|x| x.as_str()), 
-                    cookies.get("status").map(|x| x.as_str()),
-                    cookies.get("activity").map(|x| x.as_str()), 
-                    cookies.get("activity_description").map(|x| x.as_str()),
-                    None, 
-                    None
-                    ));
+                let filter = dashboard_filter(&cookies);
+
+                let data = executor::block_on(store.get_data(&page_number, &filter));
     
-                return rouille::Response::html(construct_page(data, page_number, 500));
+                let by_user = executor::block_on(store.get_message_counts_by_user(filter.guild_id, filter.user_id, 10));
+                let by_channel = executor::block_on(store.get_message_counts_by_channel(filter.guild_id, filter.user_id, 10));
+
+                rouille::Response::html(construct_page(store.as_ref(), construct_results(store.as_ref(), data, filter.guild_id), construct_message_counts(store.as_ref(), by_user, by_channel), page_number, 500))
+            },
+
+            (GET) (/music) => {
+                let cookies = parse_cookies(request);
+
+                if let Some(redirect) = require_login(&cookies, &key) {
+                    return redirect;
+                }
+
+                let guild_id = cookies.get("guild").map(|x| x.as_str());
+                let user_id = cookies.get("userId").map(|x| x.as_str());
+                let plays = executor::block_on(store.get_recent_plays(guild_id, user_id, 25));
+                let artists = executor::block_on(store.get_top_artists(guild_id, user_id, 10));
+                let tracks = executor::block_on(store.get_top_tracks(guild_id, user_id, 10));
+
+                rouille::Response::html(construct_music_page(store.as_ref(), plays, artists, tracks))
+            },
+
+            (GET) (/sessions) => {
+                let cookies = parse_cookies(request);
+
+                if let Some(redirect) = require_login(&cookies, &key) {
+                    return redirect;
+                }
+
+                let guild_id = cookies.get("guild").map(|x| x.as_str());
+                let user_id = cookies.get("userId").map(|x| x.as_str());
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                let sessions = executor::block_on(store.get_sessions(guild_id, user_id, 50));
+                let totals = executor::block_on(store.get_activity_totals(guild_id, user_id, now, 15));
+
+                rouille::Response::html(construct_sessions_page(store.as_ref(), sessions, totals, now))
+            },
+
+            (GET) (/voice) => {
+                let cookies = parse_cookies(request);
+
+                if let Some(redirect) = require_login(&cookies, &key) {
+                    return redirect;
+                }
+
+                let guild_id = cookies.get("guild").map(|x| x.as_str());
+                let user_id = cookies.get("userId").map(|x| x.as_str());
+                let events = executor::block_on(store.get_voice_events(guild_id, user_id, 100));
+
+                rouille::Response::html(construct_voice_page(store.as_ref(), events))
+            },
+
+            (GET) (/debug/query-plan) => {
+                let cookies = parse_cookies(request);
+
+                if let Some(redirect) = require_login(&cookies, &key) {
+                    return redirect;
+                }
+
+                let page_number: u64 = cookies.get("page").unwrap_or(&String::from("1")).parse().unwrap();
+                let filter = dashboard_filter(&cookies);
+                let (query, _) = database::data_query(&page_number, &filter);
+
+                match executor::block_on(store.explain_data_query(&page_number, &filter)) {
+                    Ok(plan) => rouille::Response::html(construct_query_plan_page(store.as_ref(), &query, plan)),
+                    Err(e) => rouille::Response::text(format!("Could not explain {query}: {e}")).with_status_code(500)
+                }
+            },
+
+            (GET) (/coverage) => {
+                let cookies = parse_cookies(request);
+
+                if let Some(redirect) = require_login(&cookies, &key) {
+                    return redirect;
+                }
+
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                let periods = executor::block_on(store.get_coverage(100));
+
+                rouille::Response::html(construct_coverage_page(store.as_ref(), periods, now))
+            },
+
+            (GET) (/status-messages) => {
+                let cookies = parse_cookies(request);
+
+                if let Some(redirect) = require_login(&cookies, &key) {
+                    return redirect;
+                }
+
+                let guild_id = cookies.get("guild").map(|x| x.as_str());
+                let user_id = cookies.get("userId").map(|x| x.as_str());
+                let search = cookies.get("customStatus").map(|x| x.as_str());
+                let history = executor::block_on(store.get_custom_status_history(guild_id, user_id, search, 100));
+
+                rouille::Response::html(construct_status_messages_page(store.as_ref(), history))
             },
 
             (GET) (/login) => {
                 let cookies = parse_cookies(request);
 
-                if let Some(auth_token) = cookies.get("token") {
-                    if auth_token == &key {
-                        return rouille::Response::redirect_302("/")
-                        .with_additional_header("Set-Cookie", format!("Authorization={auth_token}; max-age=10800; HttpOnly"));
-                    } 
+                if let Some(auth_token) = cookies.get("token") && auth_token == &key {
+                    return rouille::Response::redirect_302("/")
+                    .with_additional_header("Set-Cookie", format!("Authorization={auth_token}; max-age=10800; HttpOnly"));
                 }
 
-                return rouille::Response::html(
+                rouille::Response::html(
                     "
                     <head>
                         <link rel=\"stylesheet\" href=\"https://unpkg.com/@picocss/pico@latest/css/pico.min.css\" />
@@ -89,226 +158,272 @@ pub fn main(key: String) {
                         }
                     </script>
                     "
-                );
+                )
             },
 
             _ => rouille::Response::empty_404()
         )
-    });
-}
-
-async fn retrieve_data_from_db(page: u64, user_id: Option<&str>, status: Option<&str>, activity: Option<&str>, activity_description: Option<&str>, time_lt: Option<&u64>, time_mt: Option<&u64>) -> Vec<DatabaseTarget> {
-    let mut retrieved_data: Vec<DatabaseTarget> = vec!();
-
-    let mut rows: libsql::Rows = database::get_data(&page,user_id, status, activity, activity_description, time_lt, time_mt).await;
+    }).expect("Could not start the webserver");
 
-    while let Ok(Some(row)) = rows.next().await {
-        let id: u64 = row.get(0).unwrap();
-        let user_id: u64 = row.get(1).unwrap();
-        let time: u64 = row.get(2).unwrap();
-        let status: String = row.get(3).unwrap();
-        let activity: String = row.get(4).unwrap();
-        let activity_description: String = row.get(5).unwrap();
+    server.stoppable()
+}
 
-        retrieved_data.push(DatabaseTarget { id: id, user_id: user_id, time: time, status: status, activity: activity, activity_description: activity_description });
+/// The filters of the status page, read from the cookies its form sets.
+fn dashboard_filter(cookies: &HashMap<String, String>) -> database::DataFilter<'_> {
+    database::DataFilter {
+        guild_id: cookies.get("guild").map(|x| x.as_str()),
+        user_id: cookies.get("userId").map(|x| x.as_str()),
+        status: cookies.get("status").map(|x| x.as_str()),
+        activity: cookies.get("activity").map(|x| x.as_str()),
+        activity_description: cookies.get("activity_description").map(|x| x.as_str()),
+        activity_type: cookies.get("activityType").map(|x| x.as_str()),
+        activity_state: cookies.get("activityState").map(|x| x.as_str()),
+        application_id: cookies.get("applicationId").map(|x| x.as_str()),
+        platform: cookies.get("platform").map(|x| x.as_str()),
+        custom_status: cookies.get("customStatus").map(|x| x.as_str()),
+        ..Default::default()
     }
-
-    return retrieved_data;
 }
 
-
-fn construct_results(data: Vec<DatabaseTarget>) -> String {
-    let mut html_string: String = String::from("");
-    let usernames = executor::block_on(database::get_usernames(data.iter().map(|s| s.user_id).collect()));
-
-
-    for result in data.iter() {
-        let target_username = usernames.get(&result.user_id).unwrap();
-        let time: i64 = result.time.try_into().unwrap();
-
-        let readable_time = DateTime::from_timestamp(time,0).unwrap().format("%d/%m/%Y @ %H:%M:%S");
-
-        html_string += format!("
-        <article class=\"status {}\">
-            <h3><span data-userid=\"{}\"  class=\"mention\">{}</span></h3>
-            <h4>{}</h4>
-            <hr>
-            <h5>{} - {}</h5>
-        </article>
-      ", result.status, result.user_id, target_username, format!("{}", readable_time), result.activity, result.activity_description)
-            .as_str();
+fn format_timestamp(seconds: i64) -> String {
+    match DateTime::from_timestamp(seconds, 0) {
+        Some(time) => time.format("%d/%m/%Y @ %H:%M:%S").to_string(),
+        None => String::from("Unknown")
     }
-
-    return html_string;
 }
 
-fn construct_page(data: Vec<DatabaseTarget>, page: u64, max_pages: u64) -> String {
-    let html = format!(
-    "
-<html>
-<head>
-    <link rel=\"stylesheet\" href=\"https://unpkg.com/@picocss/pico@latest/css/pico.min.css\" />
-
-    <style>
-    :root {{
-    --pico-font-family-sans-serif: Inter, system-ui, \"Segoe UI\", Roboto, Oxygen, Ubuntu, Cantarell, Helvetica, Arial, \"Helvetica Neue\", sans-serif, var(--pico-font-family-emoji);
-    --pico-font-size: 87.5%;
-    /* Original: 100% */
-    --pico-line-height: 1.25;
-    /* Original: 1.5 */
-    --pico-form-element-spacing-vertical: 0.5rem;
-    /* Original: 1rem */
-    --pico-form-element-spacing-horizontal: 1.0rem;
-    /* Original: 1.25rem */
-    --pico-border-radius: 0.375rem;
-    /* Original: 0.25rem */
-}}
-
-@media (min-width: 576px) {{
-    :root {{
-        --pico-font-size: 87.5%;
-        /* Original: 106.25% */
-    }}
-}}
+fn format_duration(seconds: u64) -> String {
+    if seconds >= 3600 {
+        format!("{}h {:02}m", seconds / 3600, (seconds % 3600) / 60)
+    } else {
+        format!("{}m {:02}s", seconds / 60, seconds % 60)
+    }
+}
 
-@media (min-width: 768px) {{
-    :root {{
-        --pico-font-size: 87.5%;
-        /* Original: 112.5% */
-    }}
-}}
+/// Makes text from Discord safe to put into a page, inside elements as well as quoted attributes.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
 
-@media (min-width: 1024px) {{
-    :root {{
-        --pico-font-size: 87.5%;
-        /* Original: 118.75% */
-    }}
-}}
+/// The last known name of a user, or their id when none was recorded. Escaped for the page.
+fn username(usernames: &HashMap<u64, String>, user_id: u64) -> String {
+    escape(&usernames.get(&user_id).cloned().unwrap_or_else(|| user_id.to_string()))
+}
 
-@media (min-width: 1280px) {{
-    :root {{
-        --pico-font-size: 87.5%;
-        /* Original: 125% */
-    }}
-}}
+fn describe_activity(activity: &database::ActivityRecord) -> String {
+    let mut metadata: Vec<String> = vec!();
 
-@media (min-width: 1536px) {{
-    :root {{
-        --pico-font-size: 87.5%;
-        /* Original: 131.25% */
-    }}
-}}
+    if let Some(state) = &activity.state {
+        metadata.push(escape(state));
+    }
+    if let Some(started_at) = activity.started_at {
+        metadata.push(format!("Started {}", format_timestamp((started_at / 1000) as i64)));
+    }
+    if let Some(ends_at) = activity.ends_at {
+        metadata.push(format!("Ends {}", format_timestamp((ends_at / 1000) as i64)));
+    }
+    if let (Some(size), Some(max)) = (activity.party_size, activity.party_max) {
+        metadata.push(format!("Party {size}/{max}"));
+    }
+    if let Some(large_text) = &activity.large_text {
+        metadata.push(escape(large_text));
+    }
+    if let Some(small_text) = &activity.small_text {
+        metadata.push(escape(small_text));
+    }
+    if let Some(application_id) = activity.application_id {
+        metadata.push(format!("App {application_id}"));
+    }
+    if let Some(url) = &activity.url {
+        // Only web links become links, anything else such as javascript: is shown as text
+        let url_lower = url.to_ascii_lowercase();
+        if url_lower.starts_with("https://") || url_lower.starts_with("http://") {
+            metadata.push(format!("<a href=\"{0}\" rel=\"noopener noreferrer\">{0}</a>", escape(url)));
+        } else {
+            metadata.push(escape(url));
+        }
+    }
 
-h1,
-h2,
-h3,
-h4,
-h5,
-h6 {{
-    --pico-font-weight: 600;
-    /* Original: 700 */
-}}
+    if activity.kind == "Custom" {
+        return format!(
+            "<h5>Custom status - {} {}</h5>",
+            escape(activity.emoji.as_deref().unwrap_or("")), escape(activity.state.as_deref().unwrap_or(""))
+        );
+    }
 
-article {{
-    border: 1px solid var(--pico-muted-border-color);
-    /* Original doesn't have a border */
-    border-radius: calc(var(--pico-border-radius) * 2);
-    /* Original: var(--pico-border-radius) */
-}}
+    format!(
+        "<h5>{} {} - {}</h5><p class=\"activity-meta\">{}</p>",
+        escape(&activity.kind), escape(&activity.name), escape(activity.details.as_deref().unwrap_or("Unknown")), metadata.join(" · ")
+    )
+}
 
-article>footer {{
-    border-radius: calc(var(--pico-border-radius) * 2);
-    /* Original: var(--pico-border-radius) */
-}}
 
-        body {{
-            padding: 2em;
-        }}
+fn describe_member_event(event: &database::MemberEvent) -> String {
+    match event.kind.as_str() {
+        "join" => String::from("Joined the server"),
+        "leave" => String::from("Left the server"),
+        "nickname" => format!(
+            "Changed nickname from {} to {}",
+            escape(event.nick_before.as_deref().unwrap_or("(none)")), escape(event.nick_after.as_deref().unwrap_or("(none)"))
+        ),
+        "roles" => {
+            let mut changes: Vec<String> = vec!();
+            if !event.roles_added.is_empty() {
+                changes.push(format!("Roles added: {}", escape(&event.roles_added.join(", "))));
+            }
+            if !event.roles_removed.is_empty() {
+                changes.push(format!("Roles removed: {}", escape(&event.roles_removed.join(", "))));
+            }
+            changes.join(" · ")
+        },
+        other => escape(other)
+    }
+}
 
-        .navigation {{
-            display: flex;
-            align-items: center;
-        }}
+/// Renders the presence rows with the member events that happened in the same time span mixed in by time.
+fn construct_results(store: &dyn Store, data: Vec<database::PresenceRow>, guild_id: Option<&str>) -> String {
+    let mut entries: Vec<(u64, String)> = vec!();
+    let mut user_ids: Vec<u64> = data.iter().map(|s| s.user_id).collect();
+    user_ids.sort();
+    user_ids.dedup();
 
-        .filters {{
-            display: flex;
-            justify-content: center;
-            flex-direction: column;
-        }}
+    let from = data.iter().map(|s| s.time).min().unwrap_or(0);
+    let to = data.iter().map(|s| s.time).max().unwrap_or(0);
+    let member_events = executor::block_on(store.get_member_events(guild_id, user_ids.clone(), from, to));
 
-        .horizontal {{
-            display: flex;
-            align-items: center;
-            justify-content: center;
-            flex-direction: row;
-        }}
+    let usernames = executor::block_on(store.get_usernames(user_ids));
+    let activities = executor::block_on(store.get_activities(data.iter().map(|s| s.id).collect()));
 
-        .filters input {{}}
+    // Show everyone under the name they had when the event happened
+    let mut lookups: Vec<(u64, u64, u64)> = member_events.iter().map(|e| (e.guild_id, e.user_id, e.time)).collect();
+    lookups.extend(data.iter().filter_map(|s| s.guild_id.map(|guild_id| (guild_id, s.user_id, s.time))));
+    let names_at = executor::block_on(store.get_names_at(lookups));
 
-        .navigation * {{
-            width: fit-content;
-            height: fit-content;
-            align-items: center;
-        }}
+    // Periods without a connection are missing data, which should not read as everyone being inactive
+    if !data.is_empty() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        for (gap_start, gap_end) in executor::block_on(store.get_coverage_gaps(from, to, now)) {
+            entries.push((gap_start, format!("
+        <article class=\"status gap\">
+            <h3>No data</h3>
+            <h4>{} - {}</h4>
+            <hr>
+            <h5>The bot was not connected for {}. Nothing is known about anyone during this time.</h5>
+        </article>
+      ", format_timestamp(gap_start as i64), format_timestamp(gap_end as i64), format_duration(gap_end - gap_start))));
+        }
+    }
 
-        .navigation button {{
-            margin: 1em;
-        }}
+    for event in member_events.iter() {
+        let name = names_at.get(&(event.guild_id, event.user_id, event.time)).map(|name| escape(name)).unwrap_or_else(|| username(&usernames, event.user_id));
+        entries.push((event.time, format!("
+        <article class=\"status member-event\">
+            <h3><span data-userid=\"{}\"  class=\"mention\">{}</span></h3>
+            <h4>{}</h4>
+            <hr>
+            <h5>{}</h5>
+        </article>
+      ", event.user_id, name, format_timestamp(event.time as i64), describe_member_event(event))));
+    }
 
-        .statuses div {{
-            min-width: 50vw;
-            width: fit-content;
-            margin-bottom: 12px;
-        }}
+    for result in data.iter() {
+        let target_username = result.guild_id
+            .and_then(|guild_id| names_at.get(&(guild_id, result.user_id, result.time)))
+            .map(|name| escape(name))
+            .unwrap_or_else(|| username(&usernames, result.user_id));
+        let readable_time = format_timestamp(result.time.try_into().unwrap());
 
-        #pages * {{
-            height: 1em;
-        }}
+        let activity_list = match activities.get(&result.id) {
+            Some(entries) => entries
+                .iter()
+                .map(describe_activity)
+                .collect::<String>(),
+            // Rows written before presence_activities existed only know their first activity
+            None => format!("<h5>{} - {}</h5>", escape(&result.activity), escape(&result.activity_description))
+        };
 
-        .status {{
-            border: solid 0px;
-            border-left-width: 10px !important;
-        }}
+        let mut platforms = [("Desktop", &result.desktop_status), ("Mobile", &result.mobile_status), ("Web", &result.web_status)]
+            .iter()
+            .filter_map(|(platform, status)| status.as_ref().map(|status| format!("{platform}: {}", escape(status))))
+            .collect::<Vec<String>>();
+        match result.source.as_deref() {
+            Some("snapshot") => platforms.insert(0, String::from("Snapshot at connect")),
+            Some("offline_at_connect") => platforms.insert(0, String::from("Offline at connect")),
+            _ => {}
+        }
+        let platforms = platforms.join(" · ");
 
-        .online {{
-            border-color: rgb(40, 219, 37) !important;
-        }}
+        entries.push((result.time, format!("
+        <article class=\"status {}\">
+            <h3><span data-userid=\"{}\"  class=\"mention\">{}</span></h3>
+            <h4>{}</h4>
+            <p class=\"activity-meta\">{}</p>
+            <hr>
+            {}
+        </article>
+      ", escape(&result.status), result.user_id, target_username, readable_time, platforms, activity_list)));
+    }
 
-        .idle {{
-            border-color: rgb(219, 157, 24) !important;
-        }}
+    entries.sort_by_key(|(time, _)| *time);
+    entries.into_iter().map(|(_, html)| html).collect()
+}
 
-        .dnd {{
-            border-color: rgb(230, 54, 41) !important;
-        }}
+fn construct_message_counts(store: &dyn Store, by_user: Vec<(u64, u64)>, by_channel: Vec<(String, u64)>) -> String {
+    let usernames = executor::block_on(store.get_usernames(by_user.iter().map(|(user_id, _)| *user_id).collect()));
 
-        .offline {{
-            border-color: rgb(53, 56, 59) !important;
-        }}
+    let user_rows = by_user
+        .iter()
+        .map(|(user_id, messages)| format!("<tr><td><span data-userid=\"{user_id}\" class=\"mention\">{}</span></td><td>{messages}</td></tr>", username(&usernames, *user_id)))
+        .collect::<String>();
 
-        
-        .mention {{
-            background-color: #3e4270;
-            padding: 6px;
-            border-radius: 4px;
-        }}
-        
-        .mention::before {{
-            content: \"@\";
-        }}
+    let channel_rows = by_channel
+        .iter()
+        .map(|(channel, messages)| format!("<tr><td>#{}</td><td>{messages}</td></tr>", escape(channel)))
+        .collect::<String>();
 
-        
-    </style>
+    format!("
+        <h3>Messages</h3>
+        <table>
+            <thead><tr><th>User</th><th>Messages</th></tr></thead>
+            <tbody>{user_rows}</tbody>
+        </table>
+        <table>
+            <thead><tr><th>Channel</th><th>Messages</th></tr></thead>
+            <tbody>{channel_rows}</tbody>
+        </table>
+    ")
+}
 
-</head>
+fn construct_page(store: &dyn Store, results: String, message_counts: String, page: u64, max_pages: u64) -> String {
+    let html = format!(
+    "
+<html>
+{head}
 
 <body>
+    {navigation}
     <h1>Status</h1>
     <div class=\"filters\">
         <p>Filters</p>
         <div class=\"horizontal-filters\">
             <input id=\"id\" placeholder=\"User ID\">
             <input id=\"activity\" placeholder=\"Activity (e.g Spotify)\">
+            <input id=\"activity-state\" placeholder=\"Activity state\">
+            <input id=\"custom-status\" placeholder=\"Custom status contains\">
+            <label>Activity type</label>
+            <select id=\"activity-type\">
+                <option value=\"\" selected>Any</option>
+                <option value=\"Playing\">Playing</option>
+                <option value=\"Streaming\">Streaming</option>
+                <option value=\"Listening\">Listening</option>
+                <option value=\"Watching\">Watching</option>
+                <option value=\"Custom\">Custom</option>
+                <option value=\"Competing\">Competing</option>
+            </select>
             <label>Status</label>
             <select id=\"status\">
                 <option value=\"\" selected>Any</option>
@@ -317,6 +432,16 @@ article>footer {{
                 <option value=\"idle\">Idle</option>
                 <option value=\"offline\">Offline</option>
             </select>
+            <label>Platform</label>
+            <select id=\"platform\">
+                <option value=\"\" selected>Any</option>
+                <option value=\"desktop\">Desktop</option>
+                <option value=\"mobile\">Mobile</option>
+                <option value=\"web\">Web</option>
+                <option value=\"desktop_only\">Only desktop</option>
+                <option value=\"mobile_only\">Only mobile</option>
+                <option value=\"web_only\">Only web</option>
+            </select>
         </div>
         <div class=\"horizontal-filters\">
             <div>
@@ -334,8 +459,13 @@ article>footer {{
     <hr>
     </div>
 
-    <div class=\"statuses\">
-        {}
+    <div class=\"overview\">
+        <div class=\"statuses\">
+            {}
+        </div>
+        <aside>
+            {message_counts}
+        </aside>
     </div>
 
     <div class=\"navigation horizontal\">
@@ -351,9 +481,17 @@ article>footer {{
         const userId = document.getElementById('id');
         const activity = document.getElementById('activity');
         const status = document.getElementById('status');
+        const activityType = document.getElementById('activity-type');
+        const activityState = document.getElementById('activity-state');
+        const platform = document.getElementById('platform');
+        const customStatus = document.getElementById('custom-status');
         userId.value = getCookieByName('userId');
         activity.value = getCookieByName('activity');
         status.value = getCookieByName('status');
+        activityType.value = getCookieByName('activityType') ?? '';
+        activityState.value = getCookieByName('activityState');
+        platform.value = getCookieByName('platform') ?? '';
+        customStatus.value = getCookieByName('customStatus');
         document.cookie = \"token=no;expires=Thu, 01 Jan 1970 00:00:01 GMT\";
 
         function getCookieByName(name) {{
@@ -397,6 +535,26 @@ article>footer {{
             }} else {{
                 eraseCookie(\"status\");
             }}
+            if (activityType.value) {{
+                document.cookie = \"activityType=\" + activityType.value;
+            }} else {{
+                eraseCookie(\"activityType\");
+            }}
+            if (activityState.value) {{
+                document.cookie = \"activityState=\" + activityState.value;
+            }} else {{
+                eraseCookie(\"activityState\");
+            }}
+            if (platform.value) {{
+                document.cookie = \"platform=\" + platform.value;
+            }} else {{
+                eraseCookie(\"platform\");
+            }}
+            if (customStatus.value) {{
+                document.cookie = \"customStatus=\" + customStatus.value;
+            }} else {{
+                eraseCookie(\"customStatus\");
+            }}
             console.log(\"id=\" + userId.value + \"; activity=\" + activity.value + \"; status=\" + status.value);
             window.location.reload();
         }}
@@ -404,9 +562,521 @@ article>footer {{
 </body>
 
 </html>
-", construct_results(data));
+", results, head = page_head(), navigation = navigation(store));
+
+  html
+}
+
+fn construct_music_page(store: &dyn Store, plays: Vec<database::SpotifyPlay>, artists: Vec<(String, u64)>, tracks: Vec<(String, String, u64)>) -> String {
+    let usernames = executor::block_on(store.get_usernames(plays.iter().map(|p| p.user_id).collect()));
+
+    let mut recent = String::from("");
+    for play in plays.iter() {
+        let duration = match play.ends_at {
+            Some(ends_at) => {
+                let seconds = ends_at.saturating_sub(play.started_at);
+                format!("{}:{:02}", seconds / 60, seconds % 60)
+            },
+            None => String::from("?")
+        };
+        recent += format!("
+            <tr>
+                <td><span data-userid=\"{}\" class=\"mention\">{}</span></td>
+                <td>{}</td>
+                <td>{}</td>
+                <td>{}</td>
+                <td>{}</td>
+                <td>{}</td>
+            </tr>
+        ", play.user_id, username(&usernames, play.user_id), escape(&play.title), escape(&play.artist), escape(play.album.as_deref().unwrap_or("Unknown")),
+            format_timestamp(play.started_at as i64), duration).as_str();
+    }
+
+    let top_artists = artists
+        .iter()
+        .map(|(artist, plays)| format!("<tr><td>{}</td><td>{plays}</td></tr>", escape(artist)))
+        .collect::<String>();
+
+    let top_tracks = tracks
+        .iter()
+        .map(|(title, artist, plays)| format!("<tr><td>{}</td><td>{}</td><td>{plays}</td></tr>", escape(title), escape(artist)))
+        .collect::<String>();
+
+    format!("
+<html>
+{}
+<body>
+    {}
+    <h1>Music</h1>
+    <h2>Recent plays</h2>
+    <table>
+        <thead><tr><th>User</th><th>Track</th><th>Artist</th><th>Album</th><th>Started</th><th>Length</th></tr></thead>
+        <tbody>{recent}</tbody>
+    </table>
+    <div class=\"horizontal\">
+        <div>
+            <h2>Top artists</h2>
+            <table>
+                <thead><tr><th>Artist</th><th>Plays</th></tr></thead>
+                <tbody>{top_artists}</tbody>
+            </table>
+        </div>
+        <div>
+            <h2>Top tracks</h2>
+            <table>
+                <thead><tr><th>Track</th><th>Artist</th><th>Plays</th></tr></thead>
+                <tbody>{top_tracks}</tbody>
+            </table>
+        </div>
+    </div>
+</body>
+</html>
+", page_head(), navigation(store))
+}
+
+fn construct_sessions_page(store: &dyn Store, sessions: Vec<database::Session>, totals: Vec<(String, u64)>, now: u64) -> String {
+    let usernames = executor::block_on(store.get_usernames(sessions.iter().map(|s| s.user_id).collect()));
+
+    let mut rows = String::from("");
+    for session in sessions.iter() {
+        let ended = match session.ended_at {
+            Some(ended_at) => format_timestamp(ended_at as i64),
+            None => String::from("ongoing")
+        };
+        let duration = format_duration(session.ended_at.unwrap_or(now).saturating_sub(session.started_at));
+        let note = match session.end_reason.as_deref() {
+            Some("restart") => " (cut off by restart)",
+            _ => ""
+        };
+
+        rows += format!("
+            <tr>
+                <td><span data-userid=\"{}\" class=\"mention\">{}</span></td>
+                <td>{}</td>
+                <td>{}</td>
+                <td>{}</td>
+                <td>{}</td>
+                <td>{}{}</td>
+            </tr>
+        ", session.user_id, username(&usernames, session.user_id), escape(&session.kind), escape(&session.value),
+            format_timestamp(session.started_at as i64), ended, duration, note).as_str();
+    }
+
+    let total_rows = totals
+        .iter()
+        .map(|(activity, seconds)| format!("<tr><td>{}</td><td>{}</td></tr>", escape(activity), format_duration(*seconds)))
+        .collect::<String>();
+
+    format!("
+<html>
+{}
+<body>
+    {}
+    <h1>Sessions</h1>
+    <h2>Time per activity</h2>
+    <table>
+        <thead><tr><th>Activity</th><th>Total</th></tr></thead>
+        <tbody>{total_rows}</tbody>
+    </table>
+    <h2>Recent sessions</h2>
+    <table>
+        <thead><tr><th>User</th><th>Kind</th><th>Value</th><th>Started</th><th>Ended</th><th>Duration</th></tr></thead>
+        <tbody>{rows}</tbody>
+    </table>
+</body>
+</html>
+", page_head(), navigation(store))
+}
+
+fn construct_voice_page(store: &dyn Store, events: Vec<database::VoiceEvent>) -> String {
+    let usernames = executor::block_on(store.get_usernames(events.iter().map(|e| e.user_id).collect()));
+
+    let channel = |id: Option<u64>, name: &Option<String>| match (id, name) {
+        (Some(_), Some(name)) => format!("#{}", escape(name)),
+        (Some(id), None) => format!("#{id}"),
+        _ => String::from("")
+    };
+
+    let mut rows = String::from("");
+    for event in events.iter() {
+        let channels = match event.kind.as_str() {
+            "move" => format!("{} → {}", channel(event.previous_channel_id, &event.previous_channel_name), channel(event.channel_id, &event.channel_name)),
+            "leave" => channel(event.previous_channel_id, &event.previous_channel_name),
+            _ => channel(event.channel_id, &event.channel_name)
+        };
+
+        rows += format!("
+            <tr>
+                <td>{}</td>
+                <td><span data-userid=\"{}\" class=\"mention\">{}</span></td>
+                <td>{}</td>
+                <td>{}</td>
+            </tr>
+        ", format_timestamp(event.time as i64), event.user_id, username(&usernames, event.user_id), event.kind.replace('_', " "), channels).as_str();
+    }
+
+    format!("
+<html>
+{}
+<body>
+    {}
+    <h1>Voice</h1>
+    <table>
+        <thead><tr><th>Time</th><th>User</th><th>Event</th><th>Channel</th></tr></thead>
+        <tbody>{rows}</tbody>
+    </table>
+</body>
+</html>
+", page_head(), navigation(store))
+}
+
+fn construct_coverage_page(store: &dyn Store, periods: Vec<database::CoveragePeriod>, now: u64) -> String {
+    let mut rows = String::from("");
+    // Periods are newest first, so the gap before a period ends where the following (older) one stopped
+    for (index, period) in periods.iter().enumerate() {
+        let end = period.ended_at.unwrap_or(now);
+        let ended = match period.ended_at {
+            Some(ended_at) => format!("{} ({})", format_timestamp(ended_at as i64), period.end_reason.as_deref().unwrap_or("unknown")),
+            None => String::from("connected")
+        };
+
+        rows += format!("
+            <tr>
+                <td>{}</td>
+                <td>{} ({})</td>
+                <td>{}</td>
+                <td>{}</td>
+                <td>{}</td>
+            </tr>
+        ", period.shard_id, format_timestamp(period.started_at as i64), period.start_reason, ended,
+            format_timestamp(period.last_seen as i64), format_duration(end.saturating_sub(period.started_at))).as_str();
+
+        if let Some(previous) = periods.get(index + 1)
+            && let Some(previous_end) = previous.ended_at
+            && previous_end < period.started_at {
+            rows += format!("
+            <tr class=\"gap\">
+                <td colspan=\"4\">No data from {} to {}</td>
+                <td>{}</td>
+            </tr>
+            ", format_timestamp(previous_end as i64), format_timestamp(period.started_at as i64), format_duration(period.started_at - previous_end)).as_str();
+        }
+    }
+
+    format!("
+<html>
+{}
+<body>
+    {}
+    <h1>Coverage</h1>
+    <p>When the bot was connected and recording. Gaps between periods are missing data, not inactivity.</p>
+    <table>
+        <thead><tr><th>Shard</th><th>Connected</th><th>Disconnected</th><th>Last heartbeat</th><th>Duration</th></tr></thead>
+        <tbody>{rows}</tbody>
+    </table>
+</body>
+</html>
+", page_head(), navigation(store))
+}
 
-  return html;
+/// Plan rows are nested below their parent, and any step that scans the whole table is highlighted.
+fn construct_query_plan_page(store: &dyn Store, query: &str, plan: Vec<(i64, i64, String)>) -> String {
+    let mut depths: HashMap<i64, usize> = HashMap::new();
+    let mut steps = String::from("");
+    for (id, parent, detail) in plan.iter() {
+        let depth = depths.get(parent).map_or(0, |depth| depth + 1);
+        depths.insert(*id, depth);
+
+        let class = if detail.starts_with("SCAN") { " class=\"gap\"" } else { "" };
+        steps += format!("
+            <tr{class}>
+                <td style=\"padding-left: {}em\">{}</td>
+            </tr>
+        ", depth * 2, escape(detail)).as_str();
+    }
+
+    format!("
+<html>
+{}
+<body>
+    {}
+    <h1>Query plan</h1>
+    <p>The query behind the status page with the current filters.</p>
+    <pre>{}</pre>
+    <table>
+        <thead><tr><th>Step</th></tr></thead>
+        <tbody>{steps}</tbody>
+    </table>
+</body>
+</html>
+", page_head(), navigation(store), escape(query))
+}
+
+fn construct_status_messages_page(store: &dyn Store, history: Vec<database::CustomStatusChange>) -> String {
+    let usernames = executor::block_on(store.get_usernames(history.iter().map(|c| c.user_id).collect()));
+
+    let mut rows = String::from("");
+    for change in history.iter() {
+        let status = match &change.status {
+            Some(status) => format!("{} {}", escape(status.emoji.as_deref().unwrap_or("")), escape(status.text.as_deref().unwrap_or(""))),
+            None => String::from("<i>cleared</i>")
+        };
+
+        rows += format!("
+            <tr>
+                <td>{}</td>
+                <td><span data-userid=\"{}\" class=\"mention\">{}</span></td>
+                <td>{}</td>
+            </tr>
+        ", format_timestamp(change.time as i64), change.user_id, username(&usernames, change.user_id), status).as_str();
+    }
+
+    format!("
+<html>
+{}
+<body>
+    {}
+    <h1>Status messages</h1>
+    <p>Custom status changes. Filter by user and text with the filters on the status page.</p>
+    <table>
+        <thead><tr><th>Since</th><th>User</th><th>Status message</th></tr></thead>
+        <tbody>{rows}</tbody>
+    </table>
+</body>
+</html>
+", page_head(), navigation(store))
+}
+
+fn page_head() -> String {
+    String::from("
+<head>
+    <link rel=\"stylesheet\" href=\"https://unpkg.com/@picocss/pico@latest/css/pico.min.css\" />
+
+    <style>
+    :root {
+    --pico-font-family-sans-serif: Inter, system-ui, \"Segoe UI\", Roboto, Oxygen, Ubuntu, Cantarell, Helvetica, Arial, \"Helvetica Neue\", sans-serif, var(--pico-font-family-emoji);
+    --pico-font-size: 87.5%;
+    /* Original: 100% */
+    --pico-line-height: 1.25;
+    /* Original: 1.5 */
+    --pico-form-element-spacing-vertical: 0.5rem;
+    /* Original: 1rem */
+    --pico-form-element-spacing-horizontal: 1.0rem;
+    /* Original: 1.25rem */
+    --pico-border-radius: 0.375rem;
+    /* Original: 0.25rem */
+}
+
+@media (min-width: 576px) {
+    :root {
+        --pico-font-size: 87.5%;
+        /* Original: 106.25% */
+    }
+}
+
+@media (min-width: 768px) {
+    :root {
+        --pico-font-size: 87.5%;
+        /* Original: 112.5% */
+    }
+}
+
+@media (min-width: 1024px) {
+    :root {
+        --pico-font-size: 87.5%;
+        /* Original: 118.75% */
+    }
+}
+
+@media (min-width: 1280px) {
+    :root {
+        --pico-font-size: 87.5%;
+        /* Original: 125% */
+    }
+}
+
+@media (min-width: 1536px) {
+    :root {
+        --pico-font-size: 87.5%;
+        /* Original: 131.25% */
+    }
+}
+
+h1,
+h2,
+h3,
+h4,
+h5,
+h6 {
+    --pico-font-weight: 600;
+    /* Original: 700 */
+}
+
+article {
+    border: 1px solid var(--pico-muted-border-color);
+    /* Original doesn't have a border */
+    border-radius: calc(var(--pico-border-radius) * 2);
+    /* Original: var(--pico-border-radius) */
+}
+
+article>footer {
+    border-radius: calc(var(--pico-border-radius) * 2);
+    /* Original: var(--pico-border-radius) */
+}
+
+        body {
+            padding: 2em;
+        }
+
+        .navigation {
+            display: flex;
+            align-items: center;
+        }
+
+        .filters {
+            display: flex;
+            justify-content: center;
+            flex-direction: column;
+        }
+
+        .horizontal {
+            display: flex;
+            align-items: center;
+            justify-content: center;
+            flex-direction: row;
+        }
+
+        .filters input {}
+
+        .navigation * {
+            width: fit-content;
+            height: fit-content;
+            align-items: center;
+        }
+
+        .navigation button {
+            margin: 1em;
+        }
+
+        .overview {
+            display: flex;
+            gap: 2em;
+            align-items: flex-start;
+        }
+
+        .statuses div {
+            min-width: 50vw;
+            width: fit-content;
+            margin-bottom: 12px;
+        }
+
+        #pages * {
+            height: 1em;
+        }
+
+        .status {
+            border: solid 0px;
+            border-left-width: 10px !important;
+        }
+
+        .online {
+            border-color: rgb(40, 219, 37) !important;
+        }
+
+        .idle {
+            border-color: rgb(219, 157, 24) !important;
+        }
+
+        .dnd {
+            border-color: rgb(230, 54, 41) !important;
+        }
+
+        .offline {
+            border-color: rgb(53, 56, 59) !important;
+        }
+
+        .member-event {
+            border-color: rgb(88, 101, 242) !important;
+        }
+
+        .gap {
+            border-style: dashed !important;
+            border-color: rgb(120, 120, 120) !important;
+            color: var(--pico-muted-color);
+        }
+
+        
+        .mention {
+            background-color: #3e4270;
+            padding: 6px;
+            border-radius: 4px;
+        }
+        
+        .mention::before {
+            content: \"@\";
+        }
+
+        .activity-meta {
+            color: var(--pico-muted-color);
+        }
+
+        
+    </style>
+
+</head>")
+}
+
+fn navigation(store: &dyn Store) -> String {
+    let guilds = executor::block_on(store.get_guilds());
+    let guild_options = guilds
+        .iter()
+        .filter(|guild| guild.enabled)
+        .map(|guild| format!("<option value=\"{}\">{}</option>", guild.guild_id, escape(&guild.name)))
+        .collect::<String>();
+
+    format!("
+    <nav>
+        <ul>
+            <li><a href=\"/\">Status</a></li>
+            <li><a href=\"/music\">Music</a></li>
+            <li><a href=\"/sessions\">Sessions</a></li>
+            <li><a href=\"/voice\">Voice</a></li>
+            <li><a href=\"/status-messages\">Status messages</a></li>
+            <li><a href=\"/coverage\">Coverage</a></li>
+        </ul>
+        <ul>
+            <li>
+                <select id=\"guild\" onchange=\"handleGuildChange()\">
+                    <option value=\"\">All guilds</option>
+                    {guild_options}
+                </select>
+            </li>
+        </ul>
+    </nav>
+    <script>
+        const guild = document.getElementById('guild');
+        const guildCookie = document.cookie.split(';').map(c => c.trim()).find(c => c.startsWith('guild='));
+        guild.value = guildCookie ? guildCookie.substring(6) : '';
+
+        function handleGuildChange() {{
+            if (guild.value) {{
+                document.cookie = \"guild=\" + guild.value;
+            }} else {{
+                document.cookie = \"guild=; Max-Age=0\";
+            }}
+            document.cookie = \"page=1\";
+            window.location.reload();
+        }}
+    </script>
+    ")
+}
+
+fn require_login(cookies: &HashMap<String, String>, key: &str) -> Option<rouille::Response> {
+    match cookies.get("Authorization") {
+        Some(auth_token) if auth_token == key => None,
+        Some(_) => Some(rouille::Response::redirect_302("/login#invalid")),
+        None => Some(rouille::Response::redirect_302("/login#not-specified"))
+    }
 }
 
 fn parse_cookies(request: &rouille::Request) -> HashMap<String, String> {
@@ -420,5 +1090,5 @@ fn parse_cookies(request: &rouille::Request) -> HashMap<String, String> {
             }
         }
     }
-    return cookies;
+    cookies
 }